@@ -0,0 +1,96 @@
+use soroban_sdk::{contractclient, contracttype, Address, Env, Map, Vec};
+
+// Scale of `b_rate` and `d_rate` in Blend v2 (12 decimals)
+pub const RATE_SCALAR: i128 = 1_000_000_000_000;
+
+// Types mirror the Blend v2 pool contract field for field; `get_config` and
+// `get_reserve` results only decode if they match exactly
+
+// Blend request types accepted by `submit`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum RequestType {
+    Supply = 0,
+    Withdraw = 1,
+    SupplyCollateral = 2,
+    WithdrawCollateral = 3,
+    Borrow = 4,
+    Repay = 5,
+}
+
+// Blend Request struct
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Request {
+    pub request_type: u32,
+    pub address: Address,
+    pub amount: i128,
+}
+
+// Pool positions of an address, keyed by reserve index
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Positions {
+    pub liabilities: Map<u32, i128>, // dTokens
+    pub collateral: Map<u32, i128>, // bTokens
+    pub supply: Map<u32, i128>, // bTokens
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PoolConfig {
+    pub oracle: Address,
+    pub min_collateral: i128,
+    pub bstop_rate: u32,
+    pub status: u32, // 0-1 active, 2-3 on ice, 4-5 frozen, 6 setup
+    pub max_positions: u32,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReserveConfig {
+    pub index: u32,
+    pub decimals: u32,
+    pub c_factor: u32, // Collateral factor, scaled by 1e7
+    pub l_factor: u32, // Liability factor, scaled by 1e7
+    pub util: u32,
+    pub max_util: u32,
+    pub r_base: u32,
+    pub r_one: u32,
+    pub r_two: u32,
+    pub r_three: u32,
+    pub reactivity: u32,
+    pub supply_cap: i128,
+    pub enabled: bool,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReserveData {
+    pub d_rate: i128, // dToken to underlying, scaled by RATE_SCALAR
+    pub b_rate: i128, // bToken to underlying, scaled by RATE_SCALAR
+    pub ir_mod: i128,
+    pub b_supply: i128,
+    pub d_supply: i128,
+    pub backstop_credit: i128,
+    pub last_time: u64,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Reserve {
+    pub asset: Address,
+    pub config: ReserveConfig,
+    pub data: ReserveData,
+    pub scalar: i128,
+}
+
+// Subset of the Blend pool interface used by the hub
+#[contractclient(name = "BlendPoolClient")]
+pub trait BlendPoolInterface {
+    fn submit(env: Env, from: Address, spender: Address, to: Address, requests: Vec<Request>) -> Positions;
+    fn get_positions(env: Env, address: Address) -> Positions;
+    fn get_reserve(env: Env, asset: Address) -> Reserve;
+    fn get_config(env: Env) -> PoolConfig;
+    fn gulp(env: Env, asset: Address) -> i128;
+}
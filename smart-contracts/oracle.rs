@@ -0,0 +1,25 @@
+use soroban_sdk::{contractclient, contracttype, Address, Env, Symbol};
+
+// SEP-40 asset identifier
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Asset {
+    Stellar(Address),
+    Other(Symbol),
+}
+
+// SEP-40 price record
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PriceData {
+    pub price: i128, // Scaled by 10^decimals()
+    pub timestamp: u64,
+}
+
+// Subset of the SEP-40 price feed interface used by the hub
+#[contractclient(name = "PriceFeedClient")]
+pub trait PriceFeedInterface {
+    fn lastprice(env: Env, asset: Asset) -> Option<PriceData>;
+    fn decimals(env: Env) -> u32;
+    fn resolution(env: Env) -> u32;
+}
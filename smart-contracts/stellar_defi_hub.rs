@@ -1,17 +1,30 @@
 #![no_std]
 
 use soroban_sdk::{
-    contract, contractimpl, contracttype, symbol_short, IntoVal,
-    Address, Env, String, Vec, Map
+    contract, contracterror, contractimpl, contracttype, symbol_short, token, IntoVal,
+    Address, Env, String, Symbol, Vec, Map, U256
 };
+use soroban_sdk::auth::{ContractContext, InvokerContractAuthEntry, SubContractInvocation};
 
-// User position tracking with health factor
+pub mod blend;
+use blend::{BlendPoolClient, Positions, Request, RequestType, Reserve, RATE_SCALAR};
+
+pub mod oracle;
+use oracle::{Asset, PriceFeedClient};
+
+pub mod soroswap;
+use soroswap::SoroswapRouterClient;
+
+// User position tracking with health factor. Storage holds Blend shares in the
+// asset maps; get_user_position returns the same struct in underlying amounts
 #[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct UserPosition {
-    pub supplied_assets: Map<Address, u128>, // asset -> amount supplied to Blend
-    pub borrowed_assets: Map<Address, u128>, // asset -> amount borrowed from Blend
-    pub staked_blend: u128, // Only BLEND can be staked
-    pub rewards_earned: u128,
+    pub collateral_assets: Map<Address, i128>, // asset -> Blend collateral (bTokens stored, underlying in views)
+    pub supplied_assets: Map<Address, i128>, // asset -> lend-only Blend supply (bTokens stored, underlying in views)
+    pub borrowed_assets: Map<Address, i128>, // asset -> Blend debt (dTokens stored, underlying in views)
+    pub staked_blend: i128, // Only BLEND can be staked
+    pub rewards_earned: i128,
     pub last_reward_update: u64,
     pub health_factor: u128, // Scaled by 1e18 (1.0 = 1e18)
     pub liquidation_threshold: u128, // User-specific liquidation threshold
@@ -19,28 +32,33 @@ pub struct UserPosition {
 
 // Blend staking pool (for BLEND token only)
 #[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct BlendStakingPool {
-    pub total_staked: u128,
-    pub reward_rate: u128, // rewards per second per token staked
+    pub total_staked: i128,
+    pub reward_rate: i128, // rewards per second per token staked
     pub last_update_time: u64,
-    pub reward_per_token_stored: u128,
-    pub total_rewards_distributed: u128,
+    pub reward_per_token_stored: i128,
+    pub total_rewards_distributed: i128,
 }
 
-// Asset configuration with testnet addresses
+// Asset configuration
 #[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct AssetConfig {
     pub address: Address,
     pub symbol: String,
     pub decimals: u32,
     pub is_active: bool,
     pub ltv_ratio: u32, // Loan-to-value ratio in basis points
+    pub liquidation_threshold_bps: u32, // Collateral weight in the health factor, >= LTV
     pub reserve_index: u32, // Blend's reserve index
     pub liquidation_bonus: u32, // Bonus for liquidators in basis points
+    pub max_price_age: u64, // Seconds before an oracle price is stale
 }
 
 // Fixed exchange rates for DEX simulation
 #[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ExchangeRate {
     pub from_asset: Address,
     pub to_asset: Address,
@@ -48,8 +66,68 @@ pub struct ExchangeRate {
     pub last_update: u64,
 }
 
+// Deployment configuration passed to initialize
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HubConfig {
+    pub blend_pool: Address,
+    pub backstop: Address,
+    pub oracle: Address, // SEP-40 price feed
+    pub blend_token: Address, // BLND, the only stakeable asset
+    pub swap_router: Address, // Soroswap-compatible router
+    pub assets: Vec<AssetConfig>, // decimals are read from each token
+    pub exchange_rates: Vec<ExchangeRate>, // last_update is set on initialize
+}
+
+// How swaps between a pair of assets are executed
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SwapRoute {
+    FixedRate, // Admin-set ExchangeRate, or oracle prices, paid from the hub's balance
+    Router(Vec<Address>), // Soroswap path from token_in to token_out
+}
+
+// Pricing a swap quote was based on
+#[contracttype]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SwapSource {
+    FixedRate = 0, // Admin-set ExchangeRate
+    Oracle = 1, // Oracle cross price when no fixed rate is set
+    Router = 2, // Soroswap router path
+}
+
+// Expected result of a swap, priced the same way swaps are executed
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SwapQuote {
+    pub amount_out: i128,
+    pub fee: i128, // Protocol fee taken from amount_in
+    pub rate: u128, // Whole token_out per whole token_in, scaled by 1e18
+    pub source: SwapSource,
+    pub last_update: u64, // When the fixed rate was set, otherwise the quote time
+}
+
+// Share of protocol fees for each destination, in basis points summing to 10000
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FeeSplit {
+    pub stakers_bps: u32, // Paid to BLND stakers pro rata
+    pub treasury_bps: u32, // Withdrawn by the admin
+    pub backstop_bps: u32, // Credited to the Blend pool's backstop
+}
+
+// Protocol fees in one token held by the hub, by destination
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AccruedFees {
+    pub stakers: i128, // Distributed but not yet claimed by stakers
+    pub treasury: i128,
+    pub backstop: i128,
+}
+
 // Liquidation protection settings
 #[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct LiquidationProtection {
     pub enabled: bool,
     pub auto_repay_threshold: u128, // Health factor threshold for auto-repayment
@@ -57,16 +135,148 @@ pub struct LiquidationProtection {
     pub protection_fee: u32, // Fee for liquidation protection service
 }
 
-// Blend Request struct
+// bToken/dToken shares of an asset across all user positions
 #[contracttype]
-pub struct BlendRequest {
-    pub request_type: u32,
-    pub address: Address,
-    pub amount: i128,
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AssetTotals {
+    pub collateral: i128,
+    pub supplied: i128,
+    pub borrowed: i128,
+}
+
+// Allowed drift between hub accounting and Blend before an asset is paused
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReconcileConfig {
+    pub tolerance_bps: u32,
+    pub pause_on_drift: bool,
+}
+
+// Result of comparing hub accounting with the hub's Blend positions
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReconcileReport {
+    pub asset: Address,
+    pub hub_collateral: i128, // Collateral bTokens owed to users
+    pub blend_collateral: i128, // Collateral bTokens Blend holds for the hub
+    pub hub_supplied: i128, // Lend-only bTokens owed to users
+    pub blend_supplied: i128, // Lend-only bTokens Blend holds for the hub
+    pub hub_borrowed: i128, // dTokens owed by users
+    pub blend_borrowed: i128, // dTokens Blend holds against the hub
+    pub drift_bps: u32, // Largest relative difference
+    pub paused: bool,
+}
+
+// Oracle feeds aggregated into an asset's price
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PriceSourceConfig {
+    pub oracles: Vec<Address>, // SEP-40 price feeds
+    pub quorum: u32, // Fresh answers needed to price the asset
+    pub max_deviation_bps: u32, // Max distance of any answer from the median
+}
+
+// One oracle's answer as seen by the price aggregation
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PriceSource {
+    pub oracle: Address,
+    pub price: u128, // Scaled by 1e18, 0 if the feed did not answer
+    pub timestamp: u64,
+    pub used: bool, // Fresh answer included in the median
+}
+
+// Price used for liquidation and protection decisions
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PriceMode {
+    Spot,
+    Twap(u64), // Window in seconds
+}
+
+// Checkpoint of an asset's time-weighted price accumulator
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PriceObservation {
+    pub timestamp: u64,
+    pub price: u128, // Spot price at timestamp, scaled by 1e18
+    pub cumulative: u128, // Sum of price * seconds up to timestamp
+}
+
+// Admin-set price used while the oracles can't price an asset
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FallbackPrice {
+    pub price: u128, // Scaled by 1e18
+    pub timestamp: u64,
+    pub valid_for: u64, // Seconds after timestamp the price may be used
+}
+
+// How far a price may move before borrows and liquidations of the asset are held
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CircuitBreakerConfig {
+    pub max_move_bps: u32, // 0 disables the breaker
+    pub window: u64, // Seconds after the last accepted price a move is compared against it
+    pub grace_period: u64, // Seconds a tripped breaker holds the asset at most
+}
+
+// Last price accepted by the circuit breaker
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AcceptedPrice {
+    pub price: u128, // Scaled by 1e18
+    pub timestamp: u64,
+}
+
+// Asset held by the circuit breaker after a sudden price move
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PriceGuard {
+    pub accepted_price: u128, // Last price before the move
+    pub pending_price: u128, // Latest reading since the move
+    pub since: u64, // Time of the latest reading
+    pub until: u64, // The guard lapses after the grace period
+}
+
+// Storage keys
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DataKey {
+    Admin,
+    BlendPool,
+    Backstop,
+    Oracle,
+    BlendToken,
+    SwapRouter,
+    Initialized,
+    Position(Address),
+    Asset(Address),
+    AssetList,
+    Rate(Address, Address), // (from_asset, to_asset)
+    SwapRoute(Address, Address), // (token_in, token_out)
+    Totals(Address),
+    Paused(Address),
+    PriceSources(Address),
+    PriceHistory(Address),
+    PriceMode(Address),
+    FallbackPrice(Address),
+    CircuitBreaker,
+    LastPrice(Address),
+    PriceGuard(Address),
+    ReconcileConfig,
+    UseBlendFactors,
+    LiquidationProtection,
+    BlendStakingPool,
+    FeeSplit,
+    Fees(Address),
+    FeeIndex(Address), // Staker fees per staked BLND, scaled by 1e18
+    FeeCheckpoint(Address, Address), // (staker, token) FeeIndex at the last claim
 }
 
 // Health factor status
 #[contracttype]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum HealthStatus {
     Healthy = 0,    // > 1.15
     Warning = 1,    // 1.03 - 1.15  
@@ -74,7 +284,9 @@ pub enum HealthStatus {
     Liquidatable = 3, // < 1.0
 }
 
-#[contracttype]
+#[contracterror]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(u32)]
 pub enum HubError {
     NotInitialized = 1,
     Unauthorized = 2,
@@ -88,113 +300,173 @@ pub enum HubError {
     OnlyBlendStakeable = 10,
     PriceOracleError = 11,
     LiquidationProtectionFailed = 12,
+    DeadlineExpired = 13,
+    SlippageExceeded = 14,
+    BorrowingDisabled = 15,
+    PositionHealthy = 16,     // Liquidation attempted on a healthy position
+    PositionNotAtRisk = 17,   // Protection triggered above the auto-repay threshold
+    InsufficientCollateral = 18,
+    InsufficientDebt = 19,
+    PositionNotFound = 20,
+    InvalidAssetConfig = 21,
+    AssetAlreadyExists = 22,
+    PriceGuarded = 23,        // Circuit breaker is holding the asset after a sudden move
+    AlreadyInitialized = 24,
 }
 
 #[contract]
 pub struct StellarDeFiHub;
 
-const PROTOCOL_FEE: u128 = 50; // 0.5% (50 basis points)
+// Asset configs, Blend reserves and prices read once per invocation and
+// passed through the health factor helpers
+struct Snapshot {
+    env: Env,
+    configs: Map<Address, AssetConfig>,
+    reserves: Map<Address, Reserve>,
+    prices: Map<Address, u128>,
+    risk_prices: Map<Address, u128>,
+}
+
+impl Snapshot {
+    fn new(env: &Env) -> Self {
+        Snapshot {
+            env: env.clone(),
+            configs: Map::new(env),
+            reserves: Map::new(env),
+            prices: Map::new(env),
+            risk_prices: Map::new(env),
+        }
+    }
+
+    fn config(&mut self, asset: &Address) -> Result<AssetConfig, HubError> {
+        if let Some(config) = self.configs.get(asset.clone()) {
+            return Ok(config);
+        }
+        let config = StellarDeFiHub::get_asset_config(&self.env, asset)?;
+        self.configs.set(asset.clone(), config.clone());
+        Ok(config)
+    }
+
+    fn reserve(&mut self, asset: &Address) -> Result<Reserve, HubError> {
+        if let Some(reserve) = self.reserves.get(asset.clone()) {
+            return Ok(reserve);
+        }
+        let reserve = StellarDeFiHub::get_reserve(&self.env, asset)?;
+        self.reserves.set(asset.clone(), reserve.clone());
+        Ok(reserve)
+    }
+
+    /// Spot price, read through the oracle, fallback and circuit breaker once
+    fn price(&mut self, asset: &Address) -> Result<u128, HubError> {
+        if let Some(price) = self.prices.get(asset.clone()) {
+            return Ok(price);
+        }
+        let price = StellarDeFiHub::get_asset_price_from_oracle(&self.env, asset)?;
+        self.prices.set(asset.clone(), price);
+        Ok(price)
+    }
+
+    /// Price for liquidation and protection decisions
+    fn risk_price(&mut self, asset: &Address) -> Result<u128, HubError> {
+        if let Some(price) = self.risk_prices.get(asset.clone()) {
+            return Ok(price);
+        }
+        let spot = self.price(asset)?;
+        let price = StellarDeFiHub::get_risk_price(&self.env, asset, spot)?;
+        self.risk_prices.set(asset.clone(), price);
+        Ok(price)
+    }
+}
+
+const PROTOCOL_FEE: i128 = 50; // 0.5% (50 basis points)
 const SECONDS_PER_DAY: u64 = 86400;
 const HEALTH_FACTOR_SCALE: u128 = 1_000_000_000_000_000_000; // 1e18
-const MIN_HEALTH_FACTOR: u128 = 1_030_000_000_000_000_000; // 1.03 * 1e18
+const MIN_LTV_FACTOR: u128 = 1_000_000_000_000_000_000; // Debt may not exceed LTV-weighted collateral
 const LIQUIDATION_THRESHOLD: u128 = 1_000_000_000_000_000_000; // 1.0 * 1e18
 const AUTO_REPAY_THRESHOLD: u128 = 1_050_000_000_000_000_000; // 1.05 * 1e18
 const PRICE_SCALE: u128 = 1_000_000_000_000_000_000; // 1e18
+const MAX_LTV_RATIO: u32 = 9_999; // LTV and liquidation threshold must stay below 100%
+const MAX_LIQUIDATION_BONUS: u32 = 2_000; // 20%
+const MAX_ASSET_DECIMALS: u32 = 18; // Amounts are normalized to 18 decimals for pricing
+const SCALAR_7: u128 = 10_000_000; // Blend c_factor/l_factor scale
+const TWAP_OBSERVATION_INTERVAL: u64 = 300; // Min seconds between kept price observations
+const MAX_PRICE_OBSERVATIONS: u32 = 48; // ~4 hours of history at the minimum interval
+const MAX_TWAP_WINDOW: u64 = 3 * 3600;
+
+// Storage TTLs in ledgers (~5s per ledger)
+const DAY_IN_LEDGERS: u32 = 17280;
+const INSTANCE_BUMP_AMOUNT: u32 = 7 * DAY_IN_LEDGERS;
+const INSTANCE_LIFETIME_THRESHOLD: u32 = INSTANCE_BUMP_AMOUNT - DAY_IN_LEDGERS;
+const PERSISTENT_BUMP_AMOUNT: u32 = 30 * DAY_IN_LEDGERS;
+const PERSISTENT_LIFETIME_THRESHOLD: u32 = PERSISTENT_BUMP_AMOUNT - DAY_IN_LEDGERS;
 
 #[contractimpl]
 impl StellarDeFiHub {
     
-    /// Initialize the DeFi Hub with testnet addresses including oracle mock
+    /// Initialize the DeFi Hub with network-specific contracts and assets
     pub fn initialize(
         env: Env, 
         admin: Address,
-    ) {
+        config: HubConfig,
+    ) -> Result<(), HubError> {
         admin.require_auth();
         
-        // Testnet addresses from Blend config
-        let blend_pool = Address::from_string(&String::from_str(&env, "CCLBPEYS3XFK65MYYXSBMOGKUI4ODN5S7SUZBGD7NALUQF64QILLX5B5"));
-        let backstop = Address::from_string(&String::from_str(&env, "CC4TSDVQKBAYMK4BEDM65CSNB3ISI2A54OOBRO6IPSTFHJY3DEEKHRKV"));
-        let oracle_mock = Address::from_string(&String::from_str(&env, "CCYHURAC5VTN2ZU663UUS5F24S4GURDPO4FHZ75JLN5DMLRTLCG44H44"));
+        if env.storage().instance().has(&DataKey::Initialized) {
+            return Err(HubError::AlreadyInitialized);
+        }
         
         // Store configuration
-        env.storage().instance().set(&symbol_short!("admin"), &admin);
-        env.storage().instance().set(&symbol_short!("blend"), &blend_pool);
-        env.storage().instance().set(&symbol_short!("backstop"), &backstop);
-        env.storage().instance().set(&symbol_short!("oracle"), &oracle_mock);
-        env.storage().instance().set(&symbol_short!("init"), &true);
-        
-        // Initialize default assets from testnet
-        Self::initialize_testnet_assets(&env);
+        env.storage().instance().set(&DataKey::Admin, &admin);
+        env.storage().instance().set(&DataKey::BlendPool, &config.blend_pool);
+        env.storage().instance().set(&DataKey::Backstop, &config.backstop);
+        env.storage().instance().set(&DataKey::Oracle, &config.oracle);
+        env.storage().instance().set(&DataKey::BlendToken, &config.blend_token);
+        env.storage().instance().set(&DataKey::SwapRouter, &config.swap_router);
+        env.storage().instance().set(&DataKey::Initialized, &true);
+        Self::extend_instance_ttl(&env);
+        
+        // Initialize supported assets
+        Self::initialize_assets(&env, &config.assets)?;
         
         // Initialize fixed exchange rates
-        Self::initialize_exchange_rates(&env);
+        Self::initialize_exchange_rates(&env, &config.exchange_rates);
         
         // Initialize liquidation protection
         Self::initialize_liquidation_protection(&env);
         
+        // Report drift above 1% without pausing
+        Self::set_reconcile_config_internal(&env, &ReconcileConfig {
+            tolerance_bps: 100,
+            pause_on_drift: false,
+        });
+        
         env.events().publish(
             (symbol_short!("init"), &admin),
-            (blend_pool, backstop, oracle_mock)
+            (config.blend_pool, config.backstop, config.oracle)
         );
+
+        Ok(())
     }
     
-    /// Initialize testnet assets with liquidation bonuses
-    fn initialize_testnet_assets(env: &Env) {
-        let assets = [
-            ("CDLZFC3SYJYDZT7K67VZ75HPJVIEUVNIXF47ZG2FB2RMQQVU2HHGCYSC", "XLM", 7, 7000, 0, 500),
-            ("CB22KRA3YZVCNCQI64JQ5WE7UY2VAV7WFLK6A2JN3HEX56T2EDAFO7QF", "BLND", 7, 6500, 1, 800),
-            ("CAQCFVLOBK5GIULPNZRGATJJMIZL5BSP7X5YJVMGCPTUEPFM4AVSRCJU", "USDC", 6, 8500, 2, 300),
-            ("CAZAQB3D7KSLSNOSQKYD2V4JP5V2Y3B4RDJZRLBFCCIXDCTE3WHSY3UE", "wETH", 18, 7500, 3, 600),
-            ("CAP5AMC2OHNVREO66DFIN6DHJMPOBAJ2KCDDIMFBR7WWJH5RZBFM3UEI", "wBTC", 8, 7500, 4, 700),
-        ];
-        
-        for &(addr_str, symbol, decimals, ltv, reserve_idx, liq_bonus) in assets.iter() {
-            let address = Address::from_string(&String::from_str(env, addr_str));
-            let config = AssetConfig {
-                address: address.clone(),
-                symbol: String::from_str(env, symbol),
-                decimals,
-                is_active: true,
-                ltv_ratio: ltv,
-                reserve_index: reserve_idx,
-                liquidation_bonus: liq_bonus,
-            };
-            
-            let key = (symbol_short!("asset"), address.clone());
-            env.storage().persistent().set(&key, &config);
+    /// Initialize supported assets with liquidation bonuses
+    fn initialize_assets(env: &Env, assets: &Vec<AssetConfig>) -> Result<(), HubError> {
+        for config in assets.iter() {
+            Self::store_asset_config(env, config)?;
         }
+        Ok(())
     }
 
     /// Initialize fixed exchange rates for DEX simulation
-    fn initialize_exchange_rates(env: &Env) {
-        let xlm = Address::from_string(&String::from_str(env, "CDLZFC3SYJYDZT7K67VZ75HPJVIEUVNIXF47ZG2FB2RMQQVU2HHGCYSC"));
-        let usdc = Address::from_string(&String::from_str(env, "CAQCFVLOBK5GIULPNZRGATJJMIZL5BSP7X5YJVMGCPTUEPFM4AVSRCJU"));
-        let weth = Address::from_string(&String::from_str(env, "CAZAQB3D7KSLSNOSQKYD2V4JP5V2Y3B4RDJZRLBFCCIXDCTE3WHSY3UE"));
-        let wbtc = Address::from_string(&String::from_str(env, "CAP5AMC2OHNVREO66DFIN6DHJMPOBAJ2KCDDIMFBR7WWJH5RZBFM3UEI"));
-        let blend = Address::from_string(&String::from_str(env, "CB22KRA3YZVCNCQI64JQ5WE7UY2VAV7WFLK6A2JN3HEX56T2EDAFO7QF"));
-
-        // Fixed rates (scaled by 1e18)
-        let rates = [
-            (xlm.clone(), usdc.clone(), 120_000_000_000_000_000), // 1 XLM = 0.12 USDC
-            (usdc.clone(), xlm.clone(), 8_333_333_333_333_333_333), // 1 USDC = 8.33 XLM
-            (weth.clone(), usdc.clone(), 2500_000_000_000_000_000_000), // 1 wETH = 2500 USDC
-            (usdc.clone(), weth.clone(), 400_000_000_000_000), // 1 USDC = 0.0004 wETH
-            (wbtc.clone(), usdc.clone(), 45000_000_000_000_000_000_000), // 1 wBTC = 45000 USDC
-            (usdc.clone(), wbtc.clone(), 22_222_222_222_222), // 1 USDC = 0.000022 wBTC
-            (blend.clone(), usdc.clone(), 50_000_000_000_000_000), // 1 BLEND = 0.05 USDC
-            (usdc.clone(), blend.clone(), 20_000_000_000_000_000_000), // 1 USDC = 20 BLEND
-        ];
-
-        for (from_asset, to_asset, rate) in rates.iter() {
+    fn initialize_exchange_rates(env: &Env, rates: &Vec<ExchangeRate>) {
+        for rate in rates.iter() {
             let exchange_rate = ExchangeRate {
-                from_asset: from_asset.clone(),
-                to_asset: to_asset.clone(),
-                rate: *rate,
                 last_update: env.ledger().timestamp(),
+                ..rate
             };
             
-            let key = (symbol_short!("rate"), from_asset.clone(), to_asset.clone());
+            let key = DataKey::Rate(exchange_rate.from_asset.clone(), exchange_rate.to_asset.clone());
             env.storage().persistent().set(&key, &exchange_rate);
+            Self::extend_persistent_ttl(env, &key);
         }
     }
 
@@ -207,7 +479,8 @@ impl StellarDeFiHub {
             protection_fee: 100, // 1% fee
         };
         
-        env.storage().persistent().set(&symbol_short!("liq_prot"), &protection);
+        env.storage().persistent().set(&DataKey::LiquidationProtection, &protection);
+        Self::extend_persistent_ttl(env, &DataKey::LiquidationProtection);
     }
 
     /// Supply assets to Blend with health factor check
@@ -215,57 +488,169 @@ impl StellarDeFiHub {
         env: Env,
         user: Address,
         asset: Address,
-        amount: u128,
+        amount: i128,
         as_collateral: bool,
-    ) {
+    ) -> Result<(), HubError> {
         user.require_auth();
-        Self::require_initialized(&env);
-        
-        assert!(amount > 0, "Amount must be positive");
-        assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        Self::require_initialized(&env)?;
         
-        let blend_pool: Address = env.storage().instance().get(&symbol_short!("blend")).unwrap();
+        if amount <= 0 {
+            return Err(HubError::InvalidAmount);
+        }
+        if !Self::is_asset_supported(&env, &asset) {
+            return Err(HubError::InvalidAsset);
+        }
         
         // Check pool status
-        let pool_status = Self::get_pool_status(&env, &blend_pool);
-        assert!(pool_status <= 3, "Pool is frozen");
-        
-        // Transfer and approve
-        Self::transfer_from_user(&env, &asset, &user, &env.current_contract_address(), amount);
-        Self::approve_token(&env, &asset, &blend_pool, amount);
-        
-        // Create request
-        let request_type = if as_collateral { 2u32 } else { 0u32 };
-        let request = BlendRequest {
-            request_type,
-            address: asset.clone(),
-            amount: amount as i128,
-        };
+        let pool_status = Self::get_pool_status(&env)?;
+        if pool_status > 3 {
+            return Err(HubError::PoolFrozen);
+        }
         
-        let mut requests = Vec::new(&env);
-        requests.push_back(request);
+        // Transfer from user
+        Self::transfer_from_user(&env, &asset, &user, &env.current_contract_address(), amount)?;
         
         // Submit to Blend
-        env.invoke_contract::<()>(
-            &blend_pool,
-            &symbol_short!("submit"),
-            soroban_sdk::vec![
-                &env,
-                env.current_contract_address().into_val(&env),
-                env.current_contract_address().into_val(&env),
-                env.current_contract_address().into_val(&env),
-                requests.into_val(&env),
-            ],
-        );
+        let request_type = if as_collateral {
+            RequestType::SupplyCollateral
+        } else {
+            RequestType::Supply
+        };
+        let shares = Self::submit_to_blend(&env, request_type, &asset, amount)?;
         
         // Update position and health factor
-        Self::update_user_supply_position(&env, &user, &asset, amount);
-        Self::update_user_health_factor(&env, &user);
+        Self::update_user_supply_position(&env, &user, &asset, shares, as_collateral)?;
+        Self::update_user_health_factor(&env, &mut Snapshot::new(&env), &user)?;
         
         env.events().publish(
             (symbol_short!("supply"), &user),
             (asset, amount, as_collateral)
         );
+
+        Ok(())
+    }
+
+    /// Withdraw supplied assets from Blend with health factor check
+    pub fn withdraw_from_blend(
+        env: Env,
+        user: Address,
+        asset: Address,
+        amount: i128,
+    ) -> Result<(), HubError> {
+        user.require_auth();
+        Self::require_initialized(&env)?;
+
+        if amount <= 0 {
+            return Err(HubError::InvalidAmount);
+        }
+
+        // Lend-only balance is withdrawn first and doesn't affect health
+        let mut snapshot = Snapshot::new(&env);
+        let mut temp_position = Self::load_user_position(&env, &user);
+        let (lend_amount, collateral_amount) = Self::split_withdrawal(&env, &mut snapshot, &temp_position, &asset, amount)?;
+
+        // Check borrow limit BEFORE withdrawing collateral
+        if collateral_amount > 0 {
+            // Releasing collateral against debt is as risky as borrowing
+            if !temp_position.borrowed_assets.is_empty() {
+                Self::require_position_unguarded(&env, &mut snapshot, &temp_position)?;
+            }
+            let b_rate = snapshot.reserve(&asset)?.data.b_rate;
+            let collateral_shares = temp_position.collateral_assets.get(asset.clone()).unwrap_or(0);
+            let burned_shares = Self::amount_to_shares(&env, collateral_amount, b_rate, true);
+            temp_position.collateral_assets.set(asset.clone(), (collateral_shares - burned_shares).max(0));
+
+            let projected_ltv_factor = Self::calculate_ltv_factor(&env, &mut snapshot, &temp_position)?;
+            if projected_ltv_factor < MIN_LTV_FACTOR {
+                return Err(HubError::UnhealthyPosition);
+            }
+        }
+
+        // Withdraw from Blend, then update position and health factor
+        Self::withdraw_user_supply(&env, &user, &asset, lend_amount, collateral_amount)?;
+        Self::update_user_health_factor(&env, &mut snapshot, &user)?;
+
+        // Transfer to user
+        Self::transfer_to_user(&env, &asset, &user, amount)?;
+
+        env.events().publish(
+            (symbol_short!("withdraw"), &user),
+            (asset, amount)
+        );
+
+        Ok(())
+    }
+
+    /// Post a lend-only balance as collateral
+    pub fn enable_collateral(env: Env, user: Address, asset: Address) -> Result<(), HubError> {
+        user.require_auth();
+        Self::require_initialized(&env)?;
+        
+        if !Self::is_asset_supported(&env, &asset) {
+            return Err(HubError::InvalidAsset);
+        }
+        
+        let mut snapshot = Snapshot::new(&env);
+        let position = Self::load_user_position(&env, &user);
+        let b_rate = snapshot.reserve(&asset)?.data.b_rate;
+        let supplied_shares = position.supplied_assets.get(asset.clone()).unwrap_or(0);
+        let amount = Self::shares_to_amount(&env, supplied_shares, b_rate, false);
+        if amount <= 0 {
+            return Err(HubError::InsufficientBalance);
+        }
+        
+        // Move the balance from Blend supply to Blend collateral
+        let burned_shares = Self::submit_to_blend(&env, RequestType::Withdraw, &asset, amount)?;
+        Self::reduce_user_supply_position(&env, &user, &asset, burned_shares, false)?;
+        let minted_shares = Self::submit_to_blend(&env, RequestType::SupplyCollateral, &asset, amount)?;
+        Self::update_user_supply_position(&env, &user, &asset, minted_shares, true)?;
+        Self::update_user_health_factor(&env, &mut snapshot, &user)?;
+        
+        env.events().publish(
+            (symbol_short!("coll_on"), &user),
+            (asset, amount)
+        );
+
+        Ok(())
+    }
+
+    /// Turn a collateral balance into lend-only supply with health factor check
+    pub fn disable_collateral(env: Env, user: Address, asset: Address) -> Result<(), HubError> {
+        user.require_auth();
+        Self::require_initialized(&env)?;
+        
+        let mut snapshot = Snapshot::new(&env);
+        let mut temp_position = Self::load_user_position(&env, &user);
+        let b_rate = snapshot.reserve(&asset)?.data.b_rate;
+        let collateral_shares = temp_position.collateral_assets.get(asset.clone()).unwrap_or(0);
+        let amount = Self::shares_to_amount(&env, collateral_shares, b_rate, false);
+        if amount <= 0 {
+            return Err(HubError::InsufficientCollateral);
+        }
+        
+        // Check borrow limit BEFORE releasing the collateral
+        if !temp_position.borrowed_assets.is_empty() {
+            Self::require_position_unguarded(&env, &mut snapshot, &temp_position)?;
+        }
+        temp_position.collateral_assets.remove(asset.clone());
+        let projected_ltv_factor = Self::calculate_ltv_factor(&env, &mut snapshot, &temp_position)?;
+        if projected_ltv_factor < MIN_LTV_FACTOR {
+            return Err(HubError::UnhealthyPosition);
+        }
+        
+        // Move the balance from Blend collateral to Blend supply
+        let burned_shares = Self::submit_to_blend(&env, RequestType::WithdrawCollateral, &asset, amount)?;
+        Self::reduce_user_supply_position(&env, &user, &asset, burned_shares, true)?;
+        let minted_shares = Self::submit_to_blend(&env, RequestType::Supply, &asset, amount)?;
+        Self::update_user_supply_position(&env, &user, &asset, minted_shares, false)?;
+        Self::update_user_health_factor(&env, &mut snapshot, &user)?;
+        
+        env.events().publish(
+            (symbol_short!("coll_off"), &user),
+            (asset, amount)
+        );
+
+        Ok(())
     }
 
     /// Borrow with health factor validation and liquidation protection
@@ -273,114 +658,250 @@ impl StellarDeFiHub {
         env: Env,
         user: Address,
         asset: Address,
-        amount: u128,
-    ) {
+        amount: i128,
+    ) -> Result<(), HubError> {
         user.require_auth();
-        Self::require_initialized(&env);
-        
-        assert!(amount > 0, "Amount must be positive");
-        assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        Self::require_initialized(&env)?;
         
-        let blend_pool: Address = env.storage().instance().get(&symbol_short!("blend")).unwrap();
+        if amount <= 0 {
+            return Err(HubError::InvalidAmount);
+        }
+        if !Self::is_asset_supported(&env, &asset) {
+            return Err(HubError::InvalidAsset);
+        }
         
         // Check pool status for borrowing
-        let pool_status = Self::get_pool_status(&env, &blend_pool);
-        assert!(pool_status <= 1, "Borrowing disabled");
+        let pool_status = Self::get_pool_status(&env)?;
+        if pool_status > 1 {
+            return Err(HubError::BorrowingDisabled);
+        }
+        
+        // No new debt while the breaker holds the borrowed asset or any asset in the position
+        let mut snapshot = Snapshot::new(&env);
+        let mut temp_position = Self::load_user_position(&env, &user);
+        Self::require_price_unguarded(&env, &mut snapshot, &asset)?;
+        Self::require_position_unguarded(&env, &mut snapshot, &temp_position)?;
         
-        // Check health factor BEFORE borrowing
-        let mut temp_position = Self::get_user_position(env.clone(), user.clone());
+        // Check borrow limit BEFORE borrowing
+        let d_rate = snapshot.reserve(&asset)?.data.d_rate;
         temp_position.borrowed_assets.set(
             asset.clone(), 
-            temp_position.borrowed_assets.get(asset.clone()).unwrap_or(0) + amount
+            temp_position.borrowed_assets.get(asset.clone()).unwrap_or(0)
+                + Self::amount_to_shares(&env, amount, d_rate, true)
         );
         
-        let projected_health = Self::calculate_health_factor(&env, &temp_position);
-        assert!(projected_health >= MIN_HEALTH_FACTOR, "Would make position unhealthy");
+        let projected_ltv_factor = Self::calculate_ltv_factor(&env, &mut snapshot, &temp_position)?;
+        if projected_ltv_factor < MIN_LTV_FACTOR {
+            return Err(HubError::UnhealthyPosition);
+        }
         
         // Execute borrow
-        let request = BlendRequest {
-            request_type: 4u32, // Borrow
-            address: asset.clone(),
-            amount: amount as i128,
-        };
-        
-        let mut requests = Vec::new(&env);
-        requests.push_back(request);
-        
-        env.invoke_contract::<()>(
-            &blend_pool,
-            &symbol_short!("submit"),
-            soroban_sdk::vec![
-                &env,
-                env.current_contract_address().into_val(&env),
-                env.current_contract_address().into_val(&env),
-                env.current_contract_address().into_val(&env),
-                requests.into_val(&env),
-            ],
-        );
+        let shares = Self::submit_to_blend(&env, RequestType::Borrow, &asset, amount)?;
         
         // Update positions
-        Self::update_user_borrow_position(&env, &user, &asset, amount);
-        Self::update_user_health_factor(&env, &user);
+        Self::update_user_borrow_position(&env, &user, &asset, shares)?;
+        Self::update_user_health_factor(&env, &mut snapshot, &user)?;
         
         // Check if liquidation protection is needed
-        Self::check_and_trigger_liquidation_protection(&env, &user);
+        Self::check_and_trigger_liquidation_protection(&env, &mut snapshot, &user)?;
         
         // Transfer to user
-        Self::transfer_to_user(&env, &asset, &user, amount);
+        Self::transfer_to_user(&env, &asset, &user, amount)?;
         
         env.events().publish(
             (symbol_short!("borrow"), &user),
             (asset, amount)
         );
+
+        Ok(())
+    }
+
+    /// Repay debt to Blend for yourself or on behalf of another user
+    pub fn repay_to_blend(
+        env: Env,
+        payer: Address,
+        on_behalf_of: Address,
+        asset: Address,
+        amount: i128,
+    ) -> Result<i128, HubError> {
+        payer.require_auth();
+        Self::require_initialized(&env)?;
+
+        if amount <= 0 {
+            return Err(HubError::InvalidAmount);
+        }
+
+        // Repaying needs no prices
+        let mut snapshot = Snapshot::new(&env);
+        let position = Self::position_in_underlying(&env, &mut snapshot, &Self::load_user_position(&env, &on_behalf_of))?;
+        let current_debt = position.borrowed_assets.get(asset.clone()).unwrap_or(0);
+        if current_debt == 0 {
+            return Err(HubError::InsufficientDebt);
+        }
+
+        // Transfer from payer
+        Self::transfer_from_user(&env, &asset, &payer, &env.current_contract_address(), amount)?;
+
+        // Cap at current debt and refund the excess
+        let repay_amount = amount.min(current_debt);
+        let excess = amount - repay_amount;
+        if excess > 0 {
+            Self::transfer_to_user(&env, &asset, &payer, excess)?;
+        }
+
+        // Repay debt to Blend and update health factor
+        Self::repay_debt_to_blend(&env, &mut snapshot, &on_behalf_of, &asset, repay_amount)?;
+
+        env.events().publish(
+            (symbol_short!("repay"), &payer),
+            (on_behalf_of, asset, repay_amount)
+        );
+
+        Ok(repay_amount)
     }
 
-    /// Swap tokens with fixed exchange rates and health factor check
+    /// Swap tokens through Soroswap or fixed rates with health factor check
     pub fn swap_tokens(
         env: Env,
         user: Address,
         token_in: Address,
         token_out: Address,
-        amount_in: u128,
-        min_amount_out: u128,
+        amount_in: i128,
+        min_amount_out: i128,
         deadline: u64,
-    ) -> u128 {
+    ) -> Result<i128, HubError> {
+        let path = soroban_sdk::vec![&env, token_in, token_out];
+        Self::swap_tokens_path(env, user, path, amount_in, min_amount_out, deadline)
+    }
+
+    /// Swap along a path of assets, each hop using its pair's route; the fee is
+    /// taken once from the input and min_amount_out applies to the final output
+    pub fn swap_tokens_path(
+        env: Env,
+        user: Address,
+        path: Vec<Address>,
+        amount_in: i128,
+        min_amount_out: i128,
+        deadline: u64,
+    ) -> Result<i128, HubError> {
         user.require_auth();
-        Self::require_initialized(&env);
+        Self::require_initialized(&env)?;
         
-        assert!(amount_in > 0, "Amount must be positive");
-        assert!(env.ledger().timestamp() <= deadline, "Expired");
-        assert!(Self::is_asset_supported(&env, &token_in), "Input token not supported");
-        assert!(Self::is_asset_supported(&env, &token_out), "Output token not supported");
+        if amount_in <= 0 || min_amount_out < 0 {
+            return Err(HubError::InvalidAmount);
+        }
+        if env.ledger().timestamp() > deadline {
+            return Err(HubError::DeadlineExpired);
+        }
+        if path.len() < 2 {
+            return Err(HubError::InvalidAsset);
+        }
+        for (i, asset) in path.iter().enumerate() {
+            if !Self::is_asset_supported(&env, &asset) || path.get(i as u32 + 1) == Some(asset) {
+                return Err(HubError::InvalidAsset);
+            }
+        }
+        let token_in = path.first_unchecked();
+        let token_out = path.last_unchecked();
         
         // Calculate fee
-        let fee_amount = (amount_in * PROTOCOL_FEE) / 10000;
+        let fee_amount = Self::protocol_fee(amount_in);
         let swap_amount = amount_in - fee_amount;
         
         // Transfer from user
-        Self::transfer_from_user(&env, &token_in, &user, &env.current_contract_address(), amount_in);
+        Self::transfer_from_user(&env, &token_in, &user, &env.current_contract_address(), amount_in)?;
+        
+        // Book the protocol fee in the token it was paid in before any hop can pay it out
+        Self::accrue_fee(&env, &token_in, fee_amount);
+        
+        // Execute each hop through its pair's route; only the last one has a minimum
+        let mut amount_out = swap_amount;
+        for hop in 1..path.len() {
+            let hop_in = path.get_unchecked(hop - 1);
+            let hop_out = path.get_unchecked(hop);
+            let hop_min = if hop == path.len() - 1 { min_amount_out } else { 0 };
+            let hop_amount = amount_out;
+            amount_out = Self::execute_swap(&env, &hop_in, &hop_out, hop_amount, hop_min, deadline)?;
+            
+            env.events().publish(
+                (symbol_short!("swap_hop"), &user),
+                (hop_in, hop_out, hop_amount, amount_out)
+            );
+        }
+        
+        Self::settle_swap(&env, &user, token_in, token_out, amount_in, amount_out, fee_amount)?;
+        Ok(amount_out)
+    }
+
+    /// Buy exactly amount_out of token_out, paying at most max_amount_in of token_in
+    /// including the fee; returns the input charged
+    pub fn swap_tokens_for_exact(
+        env: Env,
+        user: Address,
+        token_in: Address,
+        token_out: Address,
+        amount_out: i128,
+        max_amount_in: i128,
+        deadline: u64,
+    ) -> Result<i128, HubError> {
+        user.require_auth();
+        Self::require_initialized(&env)?;
         
-        // Execute swap with fixed rates
-        let amount_out = Self::execute_swap_fixed_rate(&env, &token_in, &token_out, swap_amount, min_amount_out);
+        if amount_out <= 0 || max_amount_in <= 0 {
+            return Err(HubError::InvalidAmount);
+        }
+        if env.ledger().timestamp() > deadline {
+            return Err(HubError::DeadlineExpired);
+        }
+        if !Self::is_asset_supported(&env, &token_in)
+            || !Self::is_asset_supported(&env, &token_out)
+            || token_in == token_out
+        {
+            return Err(HubError::InvalidAsset);
+        }
         
-        // Store protocol fee for staking rewards
-        Self::add_to_reward_pool(&env, fee_amount);
+        // Smallest input whose swap_tokens result covers amount_out
+        let required = Self::quote_pair_input(&env, &token_in, &token_out, amount_out)?;
+        let amount_in = Self::input_with_fee(required);
+        if amount_in > max_amount_in {
+            return Err(HubError::SlippageExceeded);
+        }
+        let fee_amount = Self::protocol_fee(amount_in);
         
-        // Transfer to user
-        Self::transfer_to_user(&env, &token_out, &user, amount_out);
+        Self::transfer_from_user(&env, &token_in, &user, &env.current_contract_address(), amount_in)?;
         
-        // Update health factor if user has borrowed positions
-        Self::update_user_health_factor(&env, &user);
+        // Book the protocol fee in the token it was paid in before the swap can pay it out
+        Self::accrue_fee(&env, &token_in, fee_amount);
+        let amount_received = Self::execute_swap(&env, &token_in, &token_out, amount_in - fee_amount, amount_out, deadline)?;
         
-        // Check liquidation protection after swap
-        Self::check_and_trigger_liquidation_protection(&env, &user);
+        // Rounding only ever adds output, and the user keeps all of it
+        Self::settle_swap(&env, &user, token_in, token_out, amount_in, amount_received, fee_amount)?;
+        Ok(amount_in)
+    }
+
+    /// Expected output, fee and rate of swap_tokens for an amount, without executing it
+    pub fn quote_swap(
+        env: Env,
+        token_in: Address,
+        token_out: Address,
+        amount_in: i128,
+    ) -> Result<SwapQuote, HubError> {
+        Self::require_initialized(&env)?;
         
-        env.events().publish(
-            (symbol_short!("swap"), &user),
-            (token_in, token_out, amount_in, amount_out, fee_amount)
-        );
+        if amount_in <= 0 {
+            return Err(HubError::InvalidAmount);
+        }
+        if !Self::is_asset_supported(&env, &token_in)
+            || !Self::is_asset_supported(&env, &token_out)
+            || token_in == token_out
+        {
+            return Err(HubError::InvalidAsset);
+        }
         
-        amount_out
+        let fee = Self::protocol_fee(amount_in);
+        let mut quote = Self::quote_pair(&env, &token_in, &token_out, amount_in - fee)?;
+        quote.fee = fee;
+        Ok(quote)
     }
 
     /// Enable/disable liquidation protection for user
@@ -388,11 +909,11 @@ impl StellarDeFiHub {
         env: Env,
         user: Address,
         enabled: bool,
-    ) {
+    ) -> Result<(), HubError> {
         user.require_auth();
-        Self::require_initialized(&env);
+        Self::require_initialized(&env)?;
         
-        let mut position = Self::get_user_position(env.clone(), user.clone());
+        let mut position = Self::load_user_position(&env, &user);
         position.liquidation_threshold = if enabled {
             AUTO_REPAY_THRESHOLD
         } else {
@@ -405,260 +926,1380 @@ impl StellarDeFiHub {
             (symbol_short!("liq_prot"), &user),
             enabled
         );
+
+        Ok(())
     }
 
-    /// Manual liquidation protection trigger
+    /// Manual liquidation protection trigger (anyone can call for users who opted in)
     pub fn trigger_liquidation_protection(
         env: Env,
         user: Address,
-    ) -> u128 {
-        Self::require_initialized(&env);
-        
-        let position = Self::get_user_position(env.clone(), user.clone());
-        assert!(position.health_factor <= AUTO_REPAY_THRESHOLD, "Position not at risk");
+    ) -> Result<i128, HubError> {
+        Self::require_initialized(&env)?;
+        
+        // Collateral is only sold for users who enabled protection while it is on globally
+        let mut snapshot = Snapshot::new(&env);
+        let position = Self::load_user_health(&env, &mut snapshot, &user)?;
+        if !Self::get_liquidation_protection(&env)?.enabled || position.liquidation_threshold < AUTO_REPAY_THRESHOLD {
+            return Err(HubError::Unauthorized);
+        }
+        if position.health_factor > position.liquidation_threshold {
+            return Err(HubError::PositionNotAtRisk);
+        }
         
-        Self::execute_liquidation_protection(&env, &user)
+        Self::execute_liquidation_protection(&env, &mut snapshot, &user)
     }
 
     /// Get asset price from oracle mock
-    pub fn get_asset_price(env: Env, asset: Address) -> u128 {
-        Self::require_initialized(&env);
+    pub fn get_asset_price(env: Env, asset: Address) -> Result<u128, HubError> {
+        Self::require_initialized(&env)?;
         Self::get_asset_price_from_oracle(&env, &asset)
     }
 
-    /// Update exchange rate (admin only)
-    pub fn update_exchange_rate(
+    /// Read the oracle price of an asset into its TWAP accumulator (anyone can call)
+    pub fn poke_price(env: Env, asset: Address) -> Result<u128, HubError> {
+        Self::require_initialized(&env)?;
+        Self::extend_instance_ttl(&env);
+        Self::get_asset_price_from_oracle(&env, &asset)
+    }
+
+    /// Time-weighted average price over at least the last `window` seconds
+    pub fn get_twap(env: Env, asset: Address, window: u64) -> Result<u128, HubError> {
+        Self::require_initialized(&env)?;
+        Self::get_asset_config(&env, &asset)?;
+        Self::calculate_twap(&env, &asset, window)
+    }
+
+    /// Choose spot or TWAP prices for an asset's liquidation and protection decisions (admin only)
+    pub fn set_price_mode(env: Env, admin: Address, asset: Address, mode: PriceMode) -> Result<(), HubError> {
+        admin.require_auth();
+        Self::require_admin(&env, &admin)?;
+        
+        Self::get_asset_config(&env, &asset)?;
+        if let PriceMode::Twap(window) = mode {
+            if window == 0 || window > MAX_TWAP_WINDOW {
+                return Err(HubError::InvalidAssetConfig);
+            }
+            // Health factors would fail to price the asset until the history covers the window
+            Self::calculate_twap(&env, &asset, window)?;
+        }
+        
+        let key = DataKey::PriceMode(asset.clone());
+        env.storage().persistent().set(&key, &mode);
+        Self::extend_persistent_ttl(&env, &key);
+        
+        env.events().publish((symbol_short!("price_mod"), &admin), (asset, mode));
+
+        Ok(())
+    }
+
+    /// Set the price used for a registered asset while its oracles fail (admin only)
+    pub fn set_fallback_price(
         env: Env,
         admin: Address,
-        from_asset: Address,
-        to_asset: Address,
-        new_rate: u128,
-    ) {
+        asset: Address,
+        price: u128,
+        valid_for: u64,
+    ) -> Result<(), HubError> {
         admin.require_auth();
-        Self::require_admin(&env, &admin);
+        Self::require_admin(&env, &admin)?;
+        
+        Self::get_asset_config(&env, &asset)?;
+        if price == 0 || valid_for == 0 {
+            return Err(HubError::InvalidAmount);
+        }
+        
+        let fallback = FallbackPrice { price, timestamp: env.ledger().timestamp(), valid_for };
+        let key = DataKey::FallbackPrice(asset.clone());
+        env.storage().persistent().set(&key, &fallback);
+        Self::extend_persistent_ttl(&env, &key);
+        
+        env.events().publish((symbol_short!("fallb_set"), &admin), (asset, price, valid_for));
+
+        Ok(())
+    }
+
+    /// Configure the price move circuit breaker (admin only)
+    pub fn set_circuit_breaker(env: Env, admin: Address, config: CircuitBreakerConfig) -> Result<(), HubError> {
+        admin.require_auth();
+        Self::require_admin(&env, &admin)?;
+        
+        if config.max_move_bps > 0 && (config.window == 0 || config.grace_period == 0) {
+            return Err(HubError::InvalidAmount);
+        }
+        
+        env.storage().instance().set(&DataKey::CircuitBreaker, &config);
+        Self::extend_instance_ttl(&env);
+        
+        env.events().publish(
+            (symbol_short!("breaker"), &admin),
+            (config.max_move_bps, config.window, config.grace_period)
+        );
+
+        Ok(())
+    }
+
+    /// Accept the price that tripped the circuit breaker and resume the asset (admin only)
+    pub fn clear_price_guard(env: Env, admin: Address, asset: Address) -> Result<(), HubError> {
+        admin.require_auth();
+        Self::require_admin(&env, &admin)?;
+        
+        let key = DataKey::PriceGuard(asset.clone());
+        if let Some(guard) = env.storage().persistent().get::<_, PriceGuard>(&key) {
+            env.storage().persistent().remove(&key);
+            Self::accept_price(&env, &asset, guard.pending_price);
+            env.events().publish((symbol_short!("unguarded"), &asset), guard.pending_price);
+        }
+
+        Ok(())
+    }
+
+    /// Circuit breaker state of an asset, if it is held
+    pub fn get_price_guard(env: Env, asset: Address) -> Option<PriceGuard> {
+        env.storage().persistent().get(&DataKey::PriceGuard(asset))
+    }
+
+    /// Register a new asset (admin only); decimals are read from the token
+    pub fn add_asset(env: Env, admin: Address, config: AssetConfig) -> Result<(), HubError> {
+        admin.require_auth();
+        Self::require_admin(&env, &admin)?;
+        
+        if env.storage().persistent().has(&DataKey::Asset(config.address.clone())) {
+            return Err(HubError::AssetAlreadyExists);
+        }
+        
+        Self::store_asset_config(&env, config.clone())?;
+        
+        env.events().publish(
+            (symbol_short!("asset_add"), &admin),
+            (config.address, config.ltv_ratio, config.liquidation_threshold_bps, config.liquidation_bonus)
+        );
+
+        Ok(())
+    }
+
+    /// Update risk parameters of a registered asset (admin only); the stored
+    /// symbol and decimals are kept
+    pub fn update_asset_config(env: Env, admin: Address, config: AssetConfig) -> Result<(), HubError> {
+        admin.require_auth();
+        Self::require_admin(&env, &admin)?;
+        
+        let current = Self::get_asset_config(&env, &config.address)?;
+        Self::store_asset_config(&env, AssetConfig {
+            symbol: current.symbol,
+            ..config.clone()
+        })?;
+        
+        env.events().publish(
+            (symbol_short!("asset_upd"), &admin),
+            (
+                config.address,
+                config.ltv_ratio,
+                config.liquidation_threshold_bps,
+                config.liquidation_bonus,
+                config.reserve_index,
+                config.is_active,
+            )
+        );
+
+        Ok(())
+    }
+
+    /// Stop new supply, borrows and swaps of an asset (admin only)
+    pub fn deactivate_asset(env: Env, admin: Address, asset: Address) -> Result<(), HubError> {
+        admin.require_auth();
+        Self::require_admin(&env, &admin)?;
+        
+        let mut config = Self::get_asset_config(&env, &asset)?;
+        config.is_active = false;
+        Self::store_asset_config(&env, config)?;
+        
+        env.events().publish((symbol_short!("asset_off"), &admin), asset);
+
+        Ok(())
+    }
+
+    /// Weight health factors with Blend's c_factor/l_factor as well as the
+    /// local ratios (admin only)
+    pub fn set_blend_factors(env: Env, admin: Address, enabled: bool) -> Result<(), HubError> {
+        admin.require_auth();
+        Self::require_admin(&env, &admin)?;
+        
+        env.storage().instance().set(&DataKey::UseBlendFactors, &enabled);
+        Self::extend_instance_ttl(&env);
+        
+        env.events().publish((symbol_short!("blend_fac"), &admin), enabled);
+
+        Ok(())
+    }
+
+    /// Price an asset from the median of several oracles (admin only)
+    pub fn set_price_sources(
+        env: Env,
+        admin: Address,
+        asset: Address,
+        sources: PriceSourceConfig,
+    ) -> Result<(), HubError> {
+        admin.require_auth();
+        Self::require_admin(&env, &admin)?;
+        
+        let config = Self::get_asset_config(&env, &asset)?;
+        if sources.quorum == 0
+            || sources.quorum > sources.oracles.len()
+            || sources.max_deviation_bps > 10_000
+        {
+            return Err(HubError::InvalidAssetConfig);
+        }
+        for (i, oracle) in sources.oracles.iter().enumerate() {
+            // A repeated feed would count twice towards the quorum
+            if sources.oracles.first_index_of(&oracle) != Some(i as u32) {
+                return Err(HubError::InvalidAssetConfig);
+            }
+            if config.max_price_age < PriceFeedClient::new(&env, &oracle).resolution() as u64 {
+                return Err(HubError::InvalidAssetConfig);
+            }
+        }
+        
+        let key = DataKey::PriceSources(asset.clone());
+        env.storage().persistent().set(&key, &sources);
+        Self::extend_persistent_ttl(&env, &key);
+        
+        env.events().publish(
+            (symbol_short!("price_src"), &admin),
+            (asset, sources.oracles.len(), sources.quorum, sources.max_deviation_bps)
+        );
+
+        Ok(())
+    }
+
+    /// Current answer of each oracle pricing an asset and whether it feeds the median
+    pub fn get_price_sources(env: Env, asset: Address) -> Result<Vec<PriceSource>, HubError> {
+        Self::require_initialized(&env)?;
+        
+        let config = Self::get_asset_config(&env, &asset)?;
+        let sources = Self::get_price_source_config(&env, &asset)?;
+        Ok(Self::query_price_sources(&env, &config, &sources))
+    }
+
+    /// Get the configs of all registered assets, active or not
+    pub fn get_supported_assets(env: Env) -> Result<Vec<AssetConfig>, HubError> {
+        Self::require_initialized(&env)?;
+        
+        let mut assets = Vec::new(&env);
+        for asset in Self::get_asset_list(&env).iter() {
+            assets.push_back(Self::get_asset_config(&env, &asset)?);
+        }
+        Ok(assets)
+    }
+
+    /// Update exchange rate (admin only)
+    pub fn update_exchange_rate(
+        env: Env,
+        admin: Address,
+        from_asset: Address,
+        to_asset: Address,
+        new_rate: u128,
+    ) -> Result<(), HubError> {
+        admin.require_auth();
+        Self::require_admin(&env, &admin)?;
+        
+        let exchange_rate = ExchangeRate {
+            from_asset: from_asset.clone(),
+            to_asset: to_asset.clone(),
+            rate: new_rate,
+            last_update: env.ledger().timestamp(),
+        };
+        
+        let key = DataKey::Rate(from_asset.clone(), to_asset.clone());
+        env.storage().persistent().set(&key, &exchange_rate);
+        Self::extend_persistent_ttl(&env, &key);
+        
+        env.events().publish(
+          (symbol_short!("rate_upd"), &admin),
+            (from_asset, to_asset, new_rate)
+        );
+
+        Ok(())
+    }
+
+    /// Route swaps of a pair through Soroswap or fixed rates (admin only)
+    pub fn set_swap_route(
+        env: Env,
+        admin: Address,
+        token_in: Address,
+        token_out: Address,
+        route: SwapRoute,
+    ) -> Result<(), HubError> {
+        admin.require_auth();
+        Self::require_admin(&env, &admin)?;
+        
+        Self::get_asset_config(&env, &token_in)?;
+        Self::get_asset_config(&env, &token_out)?;
+        if token_in == token_out {
+            return Err(HubError::InvalidAsset);
+        }
+        if let SwapRoute::Router(path) = &route {
+            if path.len() < 2 || path.first() != Some(token_in.clone()) || path.last() != Some(token_out.clone()) {
+                return Err(HubError::InvalidAsset);
+            }
+        }
+        
+        let key = DataKey::SwapRoute(token_in.clone(), token_out.clone());
+        env.storage().persistent().set(&key, &route);
+        Self::extend_persistent_ttl(&env, &key);
+        
+        env.events().publish((symbol_short!("route_set"), &admin), (token_in, token_out, route));
+
+        Ok(())
+    }
+
+    /// Get user health status
+    pub fn get_health_status(env: Env, user: Address) -> Result<HealthStatus, HubError> {
+        let position = Self::load_user_health(&env, &mut Snapshot::new(&env), &user)?;
+        let health_factor = position.health_factor;
+        
+        let status = if health_factor < HEALTH_FACTOR_SCALE {
+            HealthStatus::Liquidatable
+        } else if health_factor < 1_030_000_000_000_000_000 { // 1.03
+            HealthStatus::Critical
+        } else if health_factor < 1_150_000_000_000_000_000 { // 1.15
+            HealthStatus::Warning
+        } else {
+            HealthStatus::Healthy
+        };
+
+        Ok(status)
+    }
+
+    /// Get user position with current health factor; the asset maps hold underlying
+    /// amounts at Blend's current rates, not the stored bTokens and dTokens
+    pub fn get_user_position(env: Env, user: Address) -> Result<UserPosition, HubError> {
+        Self::load_position_in_underlying(&env, &mut Snapshot::new(&env), &user)
+    }
+
+    /// Extend the storage TTL of a user position (anyone can call)
+    pub fn bump_position(env: Env, user: Address) -> Result<(), HubError> {
+        Self::require_initialized(&env)?;
+
+        let key = DataKey::Position(user);
+        if !env.storage().persistent().has(&key) {
+            return Err(HubError::PositionNotFound);
+        }
+
+        Self::extend_persistent_ttl(&env, &key);
+        Self::extend_instance_ttl(&env);
+
+        Ok(())
+    }
+
+    /// Compare hub accounting for an asset with Blend's positions (anyone can call)
+    pub fn reconcile(env: Env, asset: Address) -> Result<ReconcileReport, HubError> {
+        Self::require_initialized(&env)?;
+        
+        let config = Self::get_asset_config(&env, &asset)?;
+        let blend_pool = Self::get_blend_pool(&env)?;
+        let positions = BlendPoolClient::new(&env, &blend_pool).get_positions(&env.current_contract_address());
+        let totals = Self::get_asset_totals(&env, &asset);
+        
+        let index = config.reserve_index;
+        let blend_collateral = positions.collateral.get(index).unwrap_or(0);
+        let blend_supplied = positions.supply.get(index).unwrap_or(0);
+        let blend_borrowed = positions.liabilities.get(index).unwrap_or(0);
+        
+        let drift_bps = Self::drift_bps(totals.collateral, blend_collateral)
+            .max(Self::drift_bps(totals.supplied, blend_supplied))
+            .max(Self::drift_bps(totals.borrowed, blend_borrowed));
+        
+        let in_sync = totals.collateral == blend_collateral
+            && totals.supplied == blend_supplied
+            && totals.borrowed == blend_borrowed;
+        if !in_sync {
+            env.events().publish(
+                (symbol_short!("drift"), &asset),
+                (
+                    (totals.collateral, blend_collateral),
+                    (totals.supplied, blend_supplied),
+                    (totals.borrowed, blend_borrowed),
+                    drift_bps,
+                )
+            );
+        }
+        
+        // Pause new supply, borrows and swaps until the admin has investigated
+        let reconcile_config = Self::get_reconcile_config(&env)?;
+        if reconcile_config.pause_on_drift && drift_bps > reconcile_config.tolerance_bps {
+            let key = DataKey::Paused(asset.clone());
+            env.storage().persistent().set(&key, &true);
+            Self::extend_persistent_ttl(&env, &key);
+            
+            env.events().publish((symbol_short!("paused"), &asset), drift_bps);
+        }
+        
+        Ok(ReconcileReport {
+            paused: Self::is_asset_paused(&env, &asset),
+            asset,
+            hub_collateral: totals.collateral,
+            blend_collateral,
+            hub_supplied: totals.supplied,
+            blend_supplied,
+            hub_borrowed: totals.borrowed,
+            blend_borrowed,
+            drift_bps,
+        })
+    }
+
+    /// Set the drift tolerance used by reconcile (admin only)
+    pub fn set_reconcile_config(
+        env: Env,
+        admin: Address,
+        config: ReconcileConfig,
+    ) -> Result<(), HubError> {
+        admin.require_auth();
+        Self::require_admin(&env, &admin)?;
+        
+        Self::set_reconcile_config_internal(&env, &config);
+        
+        env.events().publish(
+            (symbol_short!("recon_cfg"), &admin),
+            (config.tolerance_bps, config.pause_on_drift)
+        );
+
+        Ok(())
+    }
+
+    /// Resume an asset paused by reconcile (admin only)
+    pub fn unpause_asset(env: Env, admin: Address, asset: Address) -> Result<(), HubError> {
+        admin.require_auth();
+        Self::require_admin(&env, &admin)?;
+        
+        env.storage().persistent().remove(&DataKey::Paused(asset.clone()));
+        
+        env.events().publish((symbol_short!("unpaused"), &admin), asset);
+
+        Ok(())
+    }
+
+    // ===================
+    // INTERNAL FUNCTIONS
+    // ===================
+
+    fn extend_instance_ttl(env: &Env) {
+        env.storage()
+            .instance()
+            .extend_ttl(INSTANCE_LIFETIME_THRESHOLD, INSTANCE_BUMP_AMOUNT);
+    }
+
+    fn extend_persistent_ttl(env: &Env, key: &DataKey) {
+        env.storage()
+            .persistent()
+            .extend_ttl(key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+    }
+
+    fn require_initialized(env: &Env) -> Result<(), HubError> {
+        if !env.storage().instance().get::<_, bool>(&DataKey::Initialized).unwrap_or(false) {
+            return Err(HubError::NotInitialized);
+        }
+        Ok(())
+    }
+
+    fn require_admin(env: &Env, user: &Address) -> Result<(), HubError> {
+        let admin: Address = env.storage()
+            .instance()
+            .get(&DataKey::Admin)
+            .ok_or(HubError::NotInitialized)?;
+        if *user != admin {
+            return Err(HubError::Unauthorized);
+        }
+        Ok(())
+    }
+
+    fn get_blend_pool(env: &Env) -> Result<Address, HubError> {
+        env.storage()
+            .instance()
+            .get(&DataKey::BlendPool)
+            .ok_or(HubError::NotInitialized)
+    }
+
+    fn get_blend_token(env: &Env) -> Result<Address, HubError> {
+        env.storage()
+            .instance()
+            .get(&DataKey::BlendToken)
+            .ok_or(HubError::NotInitialized)
+    }
+
+    fn get_pool_status(env: &Env) -> Result<u32, HubError> {
+        let blend_pool = Self::get_blend_pool(env)?;
+        Ok(BlendPoolClient::new(env, &blend_pool).get_config().status)
+    }
+
+    /// Let a contract the hub calls move `amount` of the hub's tokens to `to`
+    fn authorize_transfer(env: &Env, token: &Address, to: &Address, amount: i128) {
+        env.authorize_as_current_contract(soroban_sdk::vec![
+            env,
+            InvokerContractAuthEntry::Contract(SubContractInvocation {
+                context: ContractContext {
+                    contract: token.clone(),
+                    fn_name: Symbol::new(env, "transfer"),
+                    args: (env.current_contract_address(), to.clone(), amount).into_val(env),
+                },
+                sub_invocations: Vec::new(env),
+            }),
+        ]);
+    }
+
+    /// Submit a single request for the hub's Blend account, check the returned
+    /// positions moved the way the request asked and return the bTokens or
+    /// dTokens minted or burned
+    fn submit_to_blend(
+        env: &Env,
+        request_type: RequestType,
+        asset: &Address,
+        amount: i128,
+    ) -> Result<i128, HubError> {
+        let blend_pool = Self::get_blend_pool(env)?;
+        let reserve_index = Self::get_asset_config(env, asset)?.reserve_index;
+        let pool = BlendPoolClient::new(env, &blend_pool);
+        let hub = env.current_contract_address();
+
+        // Blend pulls supplied and repaid tokens from the spender
+        let pulls_tokens = matches!(
+            request_type,
+            RequestType::Supply | RequestType::SupplyCollateral | RequestType::Repay
+        );
+        if pulls_tokens {
+            Self::authorize_transfer(env, asset, &blend_pool, amount);
+        }
+
+        let before = pool.get_positions(&hub);
+        let requests = soroban_sdk::vec![
+            env,
+            Request {
+                request_type: request_type as u32,
+                address: asset.clone(),
+                amount,
+            },
+        ];
+        let after = match pool.try_submit(&hub, &hub, &hub, &requests) {
+            Ok(Ok(positions)) => positions,
+            _ => return Err(HubError::BlendError),
+        };
+
+        // Supply and borrow must grow the matching balance, the rest shrink it
+        let balance = |positions: &Positions| {
+            let balances = match request_type {
+                RequestType::Supply | RequestType::Withdraw => &positions.supply,
+                RequestType::SupplyCollateral | RequestType::WithdrawCollateral => &positions.collateral,
+                RequestType::Borrow | RequestType::Repay => &positions.liabilities,
+            };
+            balances.get(reserve_index).unwrap_or(0)
+        };
+        let grows = matches!(
+            request_type,
+            RequestType::Supply | RequestType::SupplyCollateral | RequestType::Borrow
+        );
+        let (before_balance, after_balance) = (balance(&before), balance(&after));
+        if (grows && after_balance <= before_balance) || (!grows && after_balance >= before_balance) {
+            return Err(HubError::BlendError);
+        }
+
+        Ok((after_balance - before_balance).abs())
+    }
+
+    fn is_asset_supported(env: &Env, asset: &Address) -> bool {
+        match Self::get_asset_config(env, asset) {
+            Ok(config) => config.is_active && !Self::is_asset_paused(env, asset),
+            Err(_) => false,
+        }
+    }
+
+    fn is_asset_paused(env: &Env, asset: &Address) -> bool {
+        env.storage().persistent().get(&DataKey::Paused(asset.clone())).unwrap_or(false)
+    }
+
+    fn get_reconcile_config(env: &Env) -> Result<ReconcileConfig, HubError> {
+        let key = DataKey::ReconcileConfig;
+        let config = env.storage().persistent().get(&key).ok_or(HubError::NotInitialized)?;
+        Self::extend_persistent_ttl(env, &key);
+        Ok(config)
+    }
+
+    fn set_reconcile_config_internal(env: &Env, config: &ReconcileConfig) {
+        env.storage().persistent().set(&DataKey::ReconcileConfig, config);
+        Self::extend_persistent_ttl(env, &DataKey::ReconcileConfig);
+    }
+
+    /// Difference between hub and Blend share counts, in basis points of Blend's
+    fn drift_bps(hub: i128, blend: i128) -> u32 {
+        let difference = (hub - blend).abs();
+        if difference == 0 {
+            return 0;
+        }
+        if blend <= 0 {
+            return 10000;
+        }
+        (difference.saturating_mul(10000) / blend).min(u32::MAX as i128) as u32
+    }
+
+    fn get_asset_totals(env: &Env, asset: &Address) -> AssetTotals {
+        let key = DataKey::Totals(asset.clone());
+        env.storage()
+            .persistent()
+            .get(&key)
+            .unwrap_or(AssetTotals { collateral: 0, supplied: 0, borrowed: 0 })
+    }
+
+    fn update_asset_totals(
+        env: &Env,
+        asset: &Address,
+        collateral_delta: i128,
+        supplied_delta: i128,
+        borrowed_delta: i128,
+    ) {
+        let key = DataKey::Totals(asset.clone());
+        let mut totals = Self::get_asset_totals(env, asset);
+        totals.collateral += collateral_delta;
+        totals.supplied += supplied_delta;
+        totals.borrowed += borrowed_delta;
+        env.storage().persistent().set(&key, &totals);
+        Self::extend_persistent_ttl(env, &key);
+    }
+
+    /// Threshold-weighted collateral over debt; liquidatable below 1.0
+    fn calculate_health_factor(env: &Env, snapshot: &mut Snapshot, position: &UserPosition) -> Result<u128, HubError> {
+        Self::calculate_collateral_factor(env, snapshot, position, false)
+    }
+
+    /// LTV-weighted collateral over debt; borrows and withdrawals must keep it at 1.0 or above
+    fn calculate_ltv_factor(env: &Env, snapshot: &mut Snapshot, position: &UserPosition) -> Result<u128, HubError> {
+        Self::calculate_collateral_factor(env, snapshot, position, true)
+    }
+
+    fn calculate_collateral_factor(
+        env: &Env,
+        snapshot: &mut Snapshot,
+        position: &UserPosition,
+        use_ltv: bool,
+    ) -> Result<u128, HubError> {
+        // Nothing needs pricing without debt, so an oracle outage can't block it
+        if position.borrowed_assets.is_empty() {
+            return Ok(HEALTH_FACTOR_SCALE * 1000);
+        }
+        let position = Self::position_in_underlying(env, snapshot, position)?;
+        let blend_factors = Self::use_blend_factors(env);
+        let mut total_collateral_value = 0u128;
+        let mut total_debt_value = 0u128;
+        
+        // Calculate weighted collateral value; lend-only supply doesn't count
+        for (asset, amount) in position.collateral_assets.iter() {
+            let price = Self::get_collateral_factor_price(snapshot, &asset, use_ltv)?;
+            let config = snapshot.config(&asset)?;
+            let value = Self::asset_value(env, amount, price, config.decimals);
+            let local_bps = if use_ltv { config.ltv_ratio } else { config.liquidation_threshold_bps };
+            
+            // Weight scaled by 1e7 like Blend; the local ratio can only be stricter
+            let mut weight = local_bps as u128 * 1000;
+            if blend_factors {
+                let reserve = Self::get_blend_reserve_config(snapshot, &config)?;
+                weight = weight.min(reserve.config.c_factor as u128);
+            }
+            total_collateral_value += Self::mul_div(env, value, weight, SCALAR_7);
+        }
+        
+        // Calculate debt value; Blend inflates liabilities by 1 / l_factor
+        for (asset, amount) in position.borrowed_assets.iter() {
+            let price = Self::get_collateral_factor_price(snapshot, &asset, use_ltv)?;
+            let config = snapshot.config(&asset)?;
+            let mut value = Self::asset_value(env, amount, price, config.decimals);
+            if blend_factors {
+                let reserve = Self::get_blend_reserve_config(snapshot, &config)?;
+                if reserve.config.l_factor == 0 {
+                    return Err(HubError::BlendError);
+                }
+                value = Self::mul_div(env, value, SCALAR_7, reserve.config.l_factor as u128);
+            }
+            total_debt_value += value;
+        }
+        
+        if total_debt_value == 0 {
+            return Ok(HEALTH_FACTOR_SCALE * 1000); // Very healthy if no debt
+        }
+        
+        Ok(Self::mul_div(env, total_collateral_value, HEALTH_FACTOR_SCALE, total_debt_value))
+    }
+
+    /// Spot prices gate new borrows and withdrawals; the health factor uses the risk price
+    fn get_collateral_factor_price(snapshot: &mut Snapshot, asset: &Address, use_ltv: bool) -> Result<u128, HubError> {
+        if use_ltv {
+            snapshot.price(asset)
+        } else {
+            snapshot.risk_price(asset)
+        }
+    }
+
+    /// a * b / denominator, widened to 256 bits so 1e18-scaled values cannot overflow
+    fn mul_div(env: &Env, a: u128, b: u128, denominator: u128) -> u128 {
+        U256::from_u128(env, a)
+            .mul(&U256::from_u128(env, b))
+            .div(&U256::from_u128(env, denominator))
+            .to_u128()
+            .expect("Result exceeds u128")
+    }
+
+    fn mul_div_ceil(env: &Env, a: u128, b: u128, denominator: u128) -> u128 {
+        let denominator = U256::from_u128(env, denominator);
+        let product = U256::from_u128(env, a).mul(&U256::from_u128(env, b));
+        let quotient = product.div(&denominator);
+        let rounded = if quotient.mul(&denominator) == product {
+            quotient
+        } else {
+            quotient.add(&U256::from_u32(env, 1))
+        };
+        rounded.to_u128().expect("Result exceeds u128")
+    }
+
+    fn get_reserve(env: &Env, asset: &Address) -> Result<Reserve, HubError> {
+        let blend_pool = Self::get_blend_pool(env)?;
+        Ok(BlendPoolClient::new(env, &blend_pool).get_reserve(asset))
+    }
+
+    /// Blend reserve of a configured asset, checked against its reserve index
+    fn get_blend_reserve_config(snapshot: &mut Snapshot, config: &AssetConfig) -> Result<Reserve, HubError> {
+        let reserve = snapshot.reserve(&config.address)?;
+        if reserve.config.index != config.reserve_index {
+            return Err(HubError::InvalidAssetConfig);
+        }
+        Ok(reserve)
+    }
+
+    fn use_blend_factors(env: &Env) -> bool {
+        env.storage().instance().get(&DataKey::UseBlendFactors).unwrap_or(false)
+    }
+
+    /// Underlying amount of bToken/dToken shares; debt rounds up
+    fn shares_to_amount(env: &Env, shares: i128, rate: i128, round_up: bool) -> i128 {
+        let amount = Self::mul_div(env, shares.max(0) as u128, rate as u128, RATE_SCALAR as u128) as i128;
+        if round_up && amount * RATE_SCALAR < shares * rate {
+            amount + 1
+        } else {
+            amount
+        }
+    }
+
+    /// bToken/dToken shares for an underlying amount; burns and new debt round up
+    fn amount_to_shares(env: &Env, amount: i128, rate: i128, round_up: bool) -> i128 {
+        let shares = Self::mul_div(env, amount.max(0) as u128, RATE_SCALAR as u128, rate as u128) as i128;
+        if round_up && shares * rate < amount * RATE_SCALAR {
+            shares + 1
+        } else {
+            shares
+        }
+    }
+
+    /// Copy of a stored position with shares converted to underlying amounts
+    fn position_in_underlying(env: &Env, snapshot: &mut Snapshot, position: &UserPosition) -> Result<UserPosition, HubError> {
+        let mut converted = position.clone();
+        for (asset, shares) in position.collateral_assets.iter() {
+            let b_rate = snapshot.reserve(&asset)?.data.b_rate;
+            converted.collateral_assets.set(asset, Self::shares_to_amount(env, shares, b_rate, false));
+        }
+        for (asset, shares) in position.supplied_assets.iter() {
+            let b_rate = snapshot.reserve(&asset)?.data.b_rate;
+            converted.supplied_assets.set(asset, Self::shares_to_amount(env, shares, b_rate, false));
+        }
+        for (asset, shares) in position.borrowed_assets.iter() {
+            let d_rate = snapshot.reserve(&asset)?.data.d_rate;
+            converted.borrowed_assets.set(asset, Self::shares_to_amount(env, shares, d_rate, true));
+        }
+        Ok(converted)
+    }
+
+    /// USD value (scaled by 1e18) of a token amount
+    fn asset_value(env: &Env, amount: i128, price: u128, decimals: u32) -> u128 {
+        Self::mul_div(env, amount.max(0) as u128, price, 10u128.pow(decimals))
+    }
+
+    /// Token amount worth a USD value (scaled by 1e18)
+    fn asset_amount(env: &Env, value: u128, price: u128, decimals: u32) -> i128 {
+        Self::mul_div(env, value, 10u128.pow(decimals), price) as i128
+    }
+
+    fn get_asset_list(env: &Env) -> Vec<Address> {
+        let key = DataKey::AssetList;
+        match env.storage().persistent().get(&key) {
+            Some(list) => {
+                Self::extend_persistent_ttl(env, &key);
+                list
+            }
+            None => Vec::new(env),
+        }
+    }
+
+    /// Validate and save an asset config, registering new assets in the list
+    fn store_asset_config(env: &Env, mut config: AssetConfig) -> Result<(), HubError> {
+        if config.liquidation_threshold_bps > MAX_LTV_RATIO
+            || config.ltv_ratio > config.liquidation_threshold_bps
+            || config.liquidation_bonus > MAX_LIQUIDATION_BONUS
+        {
+            return Err(HubError::InvalidAssetConfig);
+        }
+        
+        // A price can't be expected more often than the oracles update
+        for oracle in Self::get_price_source_config(env, &config.address)?.oracles.iter() {
+            if config.max_price_age < PriceFeedClient::new(env, &oracle).resolution() as u64 {
+                return Err(HubError::InvalidAssetConfig);
+            }
+        }
+        config.decimals = token::Client::new(env, &config.address).decimals();
+        if config.decimals > MAX_ASSET_DECIMALS {
+            return Err(HubError::InvalidAssetConfig);
+        }
+        
+        let key = DataKey::Asset(config.address.clone());
+        env.storage().persistent().set(&key, &config);
+        Self::extend_persistent_ttl(env, &key);
+        
+        let mut list = Self::get_asset_list(env);
+        if !list.contains(&config.address) {
+            list.push_back(config.address.clone());
+            env.storage().persistent().set(&DataKey::AssetList, &list);
+            Self::extend_persistent_ttl(env, &DataKey::AssetList);
+        }
+        Ok(())
+    }
+
+    fn get_asset_config(env: &Env, asset: &Address) -> Result<AssetConfig, HubError> {
+        let key = DataKey::Asset(asset.clone());
+        let config = env.storage().persistent().get(&key).ok_or(HubError::InvalidAsset)?;
+        Self::extend_persistent_ttl(env, &key);
+        Ok(config)
+    }
+
+    /// Oracles registered for an asset, or the hub oracle alone
+    fn get_price_source_config(env: &Env, asset: &Address) -> Result<PriceSourceConfig, HubError> {
+        let key = DataKey::PriceSources(asset.clone());
+        if let Some(sources) = env.storage().persistent().get(&key) {
+            Self::extend_persistent_ttl(env, &key);
+            return Ok(sources);
+        }
+        
+        let oracle: Address = env.storage()
+            .instance()
+            .get(&DataKey::Oracle)
+            .ok_or(HubError::NotInitialized)?;
+        Ok(PriceSourceConfig {
+            oracles: Vec::from_array(env, [oracle]),
+            quorum: 1,
+            max_deviation_bps: 0,
+        })
+    }
+
+    /// Ask every oracle for a price normalized to PRICE_SCALE, marking fresh answers as used
+    fn query_price_sources(env: &Env, config: &AssetConfig, sources: &PriceSourceConfig) -> Vec<PriceSource> {
+        let now = env.ledger().timestamp();
+        let mut answers = Vec::new(env);
+        
+        for oracle in sources.oracles.iter() {
+            let feed = PriceFeedClient::new(env, &oracle);
+            let mut answer = PriceSource { oracle, price: 0, timestamp: 0, used: false };
+            
+            // A failing feed is skipped instead of blocking the others
+            if let (Ok(Ok(Some(data))), Ok(Ok(decimals))) = (
+                feed.try_lastprice(&Asset::Stellar(config.address.clone())),
+                feed.try_decimals(),
+            ) {
+                if let (true, Some(scale)) = (data.price > 0, 10u128.checked_pow(decimals)) {
+                    answer.price = Self::mul_div(env, data.price as u128, PRICE_SCALE, scale);
+                    answer.timestamp = data.timestamp;
+                    // A timestamp ahead of the ledger is as untrustworthy as a stale one
+                    answer.used = answer.price > 0
+                        && data.timestamp <= now
+                        && now - data.timestamp <= config.max_price_age;
+                }
+            }
+            answers.push_back(answer);
+        }
+        answers
+    }
+
+    /// Oracle price of a registered asset, or its unexpired fallback when the oracles fail
+    fn get_asset_price_from_oracle(env: &Env, asset: &Address) -> Result<u128, HubError> {
+        let config = Self::get_asset_config(env, asset)?;
+        
+        match Self::get_oracle_median(env, &config) {
+            Ok(price) => {
+                if Self::check_price_move(env, asset, price) {
+                    Self::record_price(env, asset, price);
+                }
+                Ok(price)
+            }
+            Err(error) => Self::get_fallback_price(env, asset).ok_or(error),
+        }
+    }
+
+    /// Median of the fresh oracle prices; errors below quorum or when answers deviate too far
+    fn get_oracle_median(env: &Env, config: &AssetConfig) -> Result<u128, HubError> {
+        let sources = Self::get_price_source_config(env, &config.address)?;
+        
+        // Fresh prices in ascending order
+        let mut prices: Vec<u128> = Vec::new(env);
+        for answer in Self::query_price_sources(env, config, &sources).iter() {
+            if answer.used {
+                let index = prices.iter().position(|p| p > answer.price).unwrap_or(prices.len() as usize);
+                prices.insert(index as u32, answer.price);
+            }
+        }
+        if prices.is_empty() || prices.len() < sources.quorum {
+            return Err(HubError::PriceOracleError);
+        }
+        
+        let mid = prices.len() / 2;
+        let median = if prices.len().is_multiple_of(2) {
+            (prices.get_unchecked(mid - 1) + prices.get_unchecked(mid)) / 2
+        } else {
+            prices.get_unchecked(mid)
+        };
+        
+        // The extremes are the answers furthest from the median
+        let spread = (median - prices.first_unchecked()).max(prices.last_unchecked() - median);
+        if Self::mul_div(env, spread, 10_000, median) > sources.max_deviation_bps as u128 {
+            return Err(HubError::PriceOracleError);
+        }
+        
+        Ok(median)
+    }
+
+    /// Run a fresh price through the circuit breaker; returns whether it was accepted
+    fn check_price_move(env: &Env, asset: &Address, price: u128) -> bool {
+        let config = match env.storage().instance().get::<_, CircuitBreakerConfig>(&DataKey::CircuitBreaker) {
+            Some(config) if config.max_move_bps > 0 => config,
+            _ => return true,
+        };
+        let now = env.ledger().timestamp();
+        let guard_key = DataKey::PriceGuard(asset.clone());
+        
+        if let Some(mut guard) = env.storage().persistent().get::<_, PriceGuard>(&guard_key) {
+            // A reading in a later ledger that confirms or reverts the move clears the guard
+            let settled = now >= guard.until
+                || (now > guard.since
+                    && (Self::price_move_bps(env, guard.pending_price, price) <= config.max_move_bps
+                        || Self::price_move_bps(env, guard.accepted_price, price) <= config.max_move_bps));
+            if !settled {
+                if now > guard.since {
+                    guard.pending_price = price;
+                    guard.since = now;
+                    env.storage().persistent().set(&guard_key, &guard);
+                }
+                return false;
+            }
+            
+            env.storage().persistent().remove(&guard_key);
+            env.events().publish((symbol_short!("unguarded"), asset), price);
+        } else if let Some(last) = env.storage().persistent().get::<_, AcceptedPrice>(&DataKey::LastPrice(asset.clone())) {
+            if now <= last.timestamp + config.window
+                && Self::price_move_bps(env, last.price, price) > config.max_move_bps
+            {
+                let guard = PriceGuard {
+                    accepted_price: last.price,
+                    pending_price: price,
+                    since: now,
+                    until: now + config.grace_period,
+                };
+                env.storage().persistent().set(&guard_key, &guard);
+                Self::extend_persistent_ttl(env, &guard_key);
+                env.events().publish((symbol_short!("guarded"), asset), (last.price, price));
+                return false;
+            }
+        }
+        
+        Self::accept_price(env, asset, price);
+        true
+    }
+
+    fn accept_price(env: &Env, asset: &Address, price: u128) {
+        let key = DataKey::LastPrice(asset.clone());
+        env.storage().persistent().set(&key, &AcceptedPrice { price, timestamp: env.ledger().timestamp() });
+        Self::extend_persistent_ttl(env, &key);
+    }
+
+    /// Relative move from one price to another in basis points
+    fn price_move_bps(env: &Env, from: u128, to: u128) -> u32 {
+        Self::mul_div(env, from.abs_diff(to), 10_000, from).min(u32::MAX as u128) as u32
+    }
+
+    /// Read the asset's price, then fail while the circuit breaker holds it
+    fn require_price_unguarded(env: &Env, snapshot: &mut Snapshot, asset: &Address) -> Result<(), HubError> {
+        snapshot.price(asset)?;
+        
+        let guard: Option<PriceGuard> = env.storage().persistent().get(&DataKey::PriceGuard(asset.clone()));
+        match guard {
+            Some(guard) if env.ledger().timestamp() < guard.until => Err(HubError::PriceGuarded),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fail while the circuit breaker holds any collateral or debt asset of a position
+    fn require_position_unguarded(env: &Env, snapshot: &mut Snapshot, position: &UserPosition) -> Result<(), HubError> {
+        for asset in position.collateral_assets.keys().iter().chain(position.borrowed_assets.keys().iter()) {
+            Self::require_price_unguarded(env, snapshot, &asset)?;
+        }
+        Ok(())
+    }
+
+    /// Admin fallback price if it hasn't expired; every use is published
+    fn get_fallback_price(env: &Env, asset: &Address) -> Option<u128> {
+        let fallback: FallbackPrice = env.storage()
+            .persistent()
+            .get(&DataKey::FallbackPrice(asset.clone()))?;
+        if env.ledger().timestamp() > fallback.timestamp + fallback.valid_for {
+            return None;
+        }
         
-        let exchange_rate = ExchangeRate {
-            from_asset: from_asset.clone(),
-            to_asset: to_asset.clone(),
-            rate: new_rate,
-            last_update: env.ledger().timestamp(),
+        env.events().publish((symbol_short!("fallback"), asset), (fallback.price, fallback.timestamp));
+        Some(fallback.price)
+    }
+
+    /// Add a price to the TWAP accumulator; only the first read of a ledger counts
+    fn record_price(env: &Env, asset: &Address, price: u128) {
+        let key = DataKey::PriceHistory(asset.clone());
+        let now = env.ledger().timestamp();
+        let mut history: Vec<PriceObservation> = env.storage().persistent().get(&key).unwrap_or(Vec::new(env));
+        
+        let observation = match history.last() {
+            Some(last) if last.timestamp >= now => return,
+            Some(last) => PriceObservation {
+                timestamp: now,
+                price,
+                cumulative: last.cumulative + last.price * (now - last.timestamp) as u128,
+            },
+            None => PriceObservation { timestamp: now, price, cumulative: 0 },
         };
         
-        let key = (symbol_short!("rate"), from_asset.clone(), to_asset.clone());
-        env.storage().persistent().set(&key, &exchange_rate);
+        // Move the newest checkpoint forward until it is an interval past the one before
+        let len = history.len();
+        if len >= 2 && now - history.get_unchecked(len - 2).timestamp < TWAP_OBSERVATION_INTERVAL {
+            history.set(len - 1, observation);
+        } else {
+            history.push_back(observation);
+            if history.len() > MAX_PRICE_OBSERVATIONS {
+                history.pop_front();
+            }
+        }
         
-        env.events().publish(
-          (symbol_short!("rate_upd"), &admin),
-            (from_asset, to_asset, new_rate)
-        );
+        env.storage().persistent().set(&key, &history);
+        Self::extend_persistent_ttl(env, &key);
     }
 
-    /// Get user health status
-    pub fn get_health_status(env: Env, user: Address) -> HealthStatus {
-        let position = Self::get_user_position(env.clone(), user.clone());
-        let health_factor = position.health_factor;
-        
-        if health_factor < HEALTH_FACTOR_SCALE {
-            HealthStatus::Liquidatable
-        } else if health_factor < 1_030_000_000_000_000_000 { // 1.03
-            HealthStatus::Critical
-        } else if health_factor < 1_150_000_000_000_000_000 { // 1.15
-            HealthStatus::Warning
-        } else {
-            HealthStatus::Healthy
+    /// Average price from the newest observation at least `window` seconds old until now
+    fn calculate_twap(env: &Env, asset: &Address, window: u64) -> Result<u128, HubError> {
+        if window == 0 {
+            return Err(HubError::InvalidAmount);
         }
+        
+        let history: Vec<PriceObservation> = env.storage()
+            .persistent()
+            .get(&DataKey::PriceHistory(asset.clone()))
+            .ok_or(HubError::PriceOracleError)?;
+        let last = history.last().ok_or(HubError::PriceOracleError)?;
+        let now = env.ledger().timestamp();
+        let cumulative = last.cumulative + last.price * (now - last.timestamp) as u128;
+        
+        // Without enough history the average could be moved by a single reading
+        let start = history
+            .iter()
+            .rev()
+            .find(|observation| observation.timestamp + window <= now)
+            .ok_or(HubError::PriceOracleError)?;
+        
+        Ok((cumulative - start.cumulative) / (now - start.timestamp) as u128)
     }
 
-    /// Get user position with current health factor
-    pub fn get_user_position(env: Env, user: Address) -> UserPosition {
-        let mut position = env.storage()
+    /// Price for liquidation and protection decisions, spot or TWAP per the asset's mode;
+    /// the fresh spot price is required either way and has fed the accumulator
+    fn get_risk_price(env: &Env, asset: &Address, spot: u128) -> Result<u128, HubError> {
+        let mode = env.storage()
             .persistent()
-            .get(&(symbol_short!("pos"), user.clone()))
-            .unwrap_or(UserPosition {
-                supplied_assets: Map::new(&env),
-                borrowed_assets: Map::new(&env),
-                staked_blend: 0,
-                rewards_earned: 0,
-                last_reward_update: env.ledger().timestamp(),
-                health_factor: HEALTH_FACTOR_SCALE, // Default to 1.0
-                liquidation_threshold: LIQUIDATION_THRESHOLD,
-            });
+            .get(&DataKey::PriceMode(asset.clone()))
+            .unwrap_or(PriceMode::Spot);
+        match mode {
+            PriceMode::Spot => Ok(spot),
+            PriceMode::Twap(window) => Self::calculate_twap(env, asset, window),
+        }
+    }
+
+    /// Pay out a swap and re-check the user's position
+    fn settle_swap(
+        env: &Env,
+        user: &Address,
+        token_in: Address,
+        token_out: Address,
+        amount_in: i128,
+        amount_out: i128,
+        fee_amount: i128,
+    ) -> Result<(), HubError> {
+        // Transfer to user
+        Self::transfer_to_user(env, &token_out, user, amount_out)?;
         
-        // Update health factor
-        position.health_factor = Self::calculate_health_factor(&env, &position);
-        position
+        // Update health factor if user has borrowed positions
+        let mut snapshot = Snapshot::new(env);
+        Self::update_user_health_factor(env, &mut snapshot, user)?;
+        
+        // Check liquidation protection after swap
+        Self::check_and_trigger_liquidation_protection(env, &mut snapshot, user)?;
+        
+        env.events().publish(
+            (symbol_short!("swap"), user),
+            (token_in, token_out, amount_in, amount_out, fee_amount)
+        );
+        Ok(())
     }
 
-    // ===================
-    // INTERNAL FUNCTIONS
-    // ===================
+    fn protocol_fee(amount: i128) -> i128 {
+        (amount * PROTOCOL_FEE) / 10000
+    }
 
-    fn require_initialized(env: &Env) {
-        assert!(
-            env.storage().instance().get::<_, bool>(&symbol_short!("init")).unwrap_or(false),
-            "Contract not initialized"
-        );
+    /// Smallest input that still leaves swap_amount after the protocol fee
+    fn input_with_fee(swap_amount: i128) -> i128 {
+        let mut amount_in = (swap_amount * 10000 + 10000 - PROTOCOL_FEE - 1) / (10000 - PROTOCOL_FEE);
+        // The fee rounds down, so a slightly smaller input can still cover the swap
+        while amount_in - 1 - Self::protocol_fee(amount_in - 1) >= swap_amount {
+            amount_in -= 1;
+        }
+        amount_in
     }
 
-    fn require_admin(env: &Env, user: &Address) {
-        let admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
-        assert_eq!(*user, admin, "Only admin allowed");
+    fn get_swap_route(env: &Env, token_in: &Address, token_out: &Address) -> SwapRoute {
+        let key = DataKey::SwapRoute(token_in.clone(), token_out.clone());
+        match env.storage().persistent().get(&key) {
+            Some(route) => {
+                Self::extend_persistent_ttl(env, &key);
+                route
+            }
+            None => SwapRoute::FixedRate,
+        }
     }
 
-    fn get_pool_status(env: &Env, blend_pool: &Address) -> u32 {
-        env.invoke_contract::<u32>(
-            blend_pool,
-            &symbol_short!("get_stat"),
-            soroban_sdk::vec![env],
-        )
+    fn get_swap_router(env: &Env) -> Result<SoroswapRouterClient<'_>, HubError> {
+        let router: Address = env.storage()
+            .instance()
+            .get(&DataKey::SwapRouter)
+            .ok_or(HubError::NotInitialized)?;
+        Ok(SoroswapRouterClient::new(env, &router))
     }
 
-    fn is_asset_supported(env: &Env, asset: &Address) -> bool {
-        let key = (symbol_short!("asset"), asset.clone());
-        if let Some(config) = env.storage().persistent().get::<_, AssetConfig>(&key) {
-            config.is_active
-        } else {
-            false
+    /// Swap the hub's tokens along the pair's route; fixed rates unless a router path is set
+    fn execute_swap(
+        env: &Env,
+        token_in: &Address,
+        token_out: &Address,
+        amount_in: i128,
+        min_amount_out: i128,
+        deadline: u64,
+    ) -> Result<i128, HubError> {
+        match Self::get_swap_route(env, token_in, token_out) {
+            SwapRoute::FixedRate => Self::execute_swap_fixed_rate(env, token_in, token_out, amount_in, min_amount_out),
+            SwapRoute::Router(path) => Self::execute_swap_router(env, &path, amount_in, min_amount_out, deadline),
         }
     }
 
-    fn calculate_health_factor(env: &Env, position: &UserPosition) -> u128 {
-        let mut total_collateral_value = 0u128;
-        let mut total_debt_value = 0u128;
+    /// Quote a swap along the pair's route with the pricing execute_swap uses, before fees
+    fn quote_pair(env: &Env, token_in: &Address, token_out: &Address, amount_in: i128) -> Result<SwapQuote, HubError> {
+        let path = match Self::get_swap_route(env, token_in, token_out) {
+            SwapRoute::FixedRate => return Self::quote_fixed_rate(env, token_in, token_out, amount_in),
+            SwapRoute::Router(path) => path,
+        };
         
-        // Calculate collateral value (with LTV)
-        for (asset, amount) in position.supplied_assets.iter() {
-            let price = Self::get_asset_price_from_oracle(env, &asset);
-            let config = Self::get_asset_config(env, &asset);
-            let value = (amount * price) / (10u128.pow(config.decimals));
-            let ltv_adjusted_value = (value * config.ltv_ratio as u128) / 10000;
-            total_collateral_value += ltv_adjusted_value;
-        }
+        let amount_out = match Self::get_swap_router(env)?.try_router_get_amounts_out(&amount_in, &path) {
+            Ok(Ok(amounts)) => amounts.last().ok_or(HubError::SwapFailed)?,
+            _ => return Err(HubError::SwapFailed),
+        };
         
-        // Calculate debt value
-        for (asset, amount) in position.borrowed_assets.iter() {
-            let price = Self::get_asset_price_from_oracle(env, &asset);
-            let config = Self::get_asset_config(env, &asset);
-            let value = (amount * price) / (10u128.pow(config.decimals));
-            total_debt_value += value;
-        }
+        // Effective rate between whole tokens
+        let decimals_in = Self::get_asset_config(env, token_in)?.decimals;
+        let decimals_out = Self::get_asset_config(env, token_out)?.decimals;
+        let raw_rate = Self::mul_div(env, amount_out.max(0) as u128, PRICE_SCALE, amount_in as u128);
+        Ok(SwapQuote {
+            amount_out,
+            fee: 0,
+            rate: Self::mul_div(env, raw_rate, 10u128.pow(decimals_in), 10u128.pow(decimals_out)),
+            source: SwapSource::Router,
+            last_update: env.ledger().timestamp(),
+        })
+    }
+
+    /// Smallest input, before fees, that quote_pair prices at amount_out or more
+    fn quote_pair_input(env: &Env, token_in: &Address, token_out: &Address, amount_out: i128) -> Result<i128, HubError> {
+        let path = match Self::get_swap_route(env, token_in, token_out) {
+            SwapRoute::FixedRate => return Self::quote_fixed_rate_input(env, token_in, token_out, amount_out),
+            SwapRoute::Router(path) => path,
+        };
         
-        if total_debt_value == 0 {
-            return HEALTH_FACTOR_SCALE * 1000; // Very healthy if no debt
+        match Self::get_swap_router(env)?.try_router_get_amounts_in(&amount_out, &path) {
+            Ok(Ok(amounts)) => amounts.first().ok_or(HubError::SwapFailed),
+            _ => Err(HubError::SwapFailed),
+        }
+    }
+
+    /// Swap through the Soroswap router, returning what the hub actually received
+    fn execute_swap_router(
+        env: &Env,
+        path: &Vec<Address>,
+        amount_in: i128,
+        min_amount_out: i128,
+        deadline: u64,
+    ) -> Result<i128, HubError> {
+        let router = Self::get_swap_router(env)?;
+        let hub = env.current_contract_address();
+        let token_in = path.get_unchecked(0);
+        let token_out = token::Client::new(env, &path.last_unchecked());
+        
+        // The router moves the input from the hub into the first pair
+        let pair = match router.try_router_pair_for(&token_in, &path.get_unchecked(1)) {
+            Ok(Ok(pair)) => pair,
+            _ => return Err(HubError::SwapFailed),
+        };
+        Self::authorize_transfer(env, &token_in, &pair, amount_in);
+        
+        let balance_before = token_out.balance(&hub);
+        if !matches!(
+            router.try_swap_exact_tokens_for_tokens(&amount_in, &min_amount_out, path, &hub, &deadline),
+            Ok(Ok(_))
+        ) {
+            return Err(HubError::SwapFailed);
         }
         
-        (total_collateral_value * HEALTH_FACTOR_SCALE) / total_debt_value
+        let amount_out = token_out.balance(&hub) - balance_before;
+        if amount_out < min_amount_out {
+            return Err(HubError::SlippageExceeded);
+        }
+        Ok(amount_out)
     }
 
-    fn get_asset_config(env: &Env, asset: &Address) -> AssetConfig {
-        let key = (symbol_short!("asset"), asset.clone());
-        env.storage().persistent().get(&key).unwrap()
+    fn execute_swap_fixed_rate(
+        env: &Env,
+        token_in: &Address,
+        token_out: &Address,
+        amount_in: i128,
+        min_amount_out: i128,
+    ) -> Result<i128, HubError> {
+        let quote = Self::quote_fixed_rate(env, token_in, token_out, amount_in)?;
+        if quote.amount_out < min_amount_out {
+            return Err(HubError::SlippageExceeded);
+        }
+        // Fixed-rate swaps pay from the hub's own balance, never from fees or stakes
+        if quote.amount_out > Self::available_balance(env, token_out)? {
+            return Err(HubError::InsufficientBalance);
+        }
+        Ok(quote.amount_out)
     }
 
-    fn get_asset_price_from_oracle(env: &Env, asset: &Address) -> u128 {
-        let oracle_mock: Address = env.storage().instance().get(&symbol_short!("oracle")).unwrap();
-        
-        // Call oracle mock to get price
-        let price = env.invoke_contract::<u128>(
-            &oracle_mock,
-            &symbol_short!("get_price"),
-            soroban_sdk::vec![env, asset.into_val(env)],
-        );
-        
-        // Fallback to fixed prices if oracle fails
-        if price == 0 {
-            Self::get_fallback_price(env, asset)
-        } else {
-            price
-        }
-    }
-
-    fn get_fallback_price(env: &Env, asset: &Address) -> u128 {
-        // Fallback fixed prices (scaled by 1e18)
-        let xlm = Address::from_string(&String::from_str(env, "CDLZFC3SYJYDZT7K67VZ75HPJVIEUVNIXF47ZG2FB2RMQQVU2HHGCYSC"));
-        let usdc = Address::from_string(&String::from_str(env, "CAQCFVLOBK5GIULPNZRGATJJMIZL5BSP7X5YJVMGCPTUEPFM4AVSRCJU"));
-        let weth = Address::from_string(&String::from_str(env, "CAZAQB3D7KSLSNOSQKYD2V4JP5V2Y3B4RDJZRLBFCCIXDCTE3WHSY3UE"));
-        let wbtc = Address::from_string(&String::from_str(env, "CAP5AMC2OHNVREO66DFIN6DHJMPOBAJ2KCDDIMFBR7WWJH5RZBFM3UEI"));
-        let blend = Address::from_string(&String::from_str(env, "CB22KRA3YZVCNCQI64JQ5WE7UY2VAV7WFLK6A2JN3HEX56T2EDAFO7QF"));
-
-        if *asset == xlm {
-            120_000_000_000_000_000 // $0.12
-        } else if *asset == usdc {
-            PRICE_SCALE // $1.00
-        } else if *asset == weth {
-            2500_000_000_000_000_000_000 // $2500
-        } else if *asset == wbtc {
-            45000_000_000_000_000_000_000 // $45000
-        } else if *asset == blend {
-            50_000_000_000_000_000 // $0.05
+    /// Inverse of quote_fixed_rate, rounding each step up against the user
+    fn quote_fixed_rate_input(
+        env: &Env,
+        token_in: &Address,
+        token_out: &Address,
+        amount_out: i128,
+    ) -> Result<i128, HubError> {
+        let key = DataKey::Rate(token_in.clone(), token_out.clone());
+        let config_in = Self::get_asset_config(env, token_in)?;
+        let config_out = Self::get_asset_config(env, token_out)?;
+        
+        let amount_in = if let Some(exchange_rate) = env.storage().persistent().get::<_, ExchangeRate>(&key) {
+            Self::extend_persistent_ttl(env, &key);
+            
+            let amount_out_normalized = amount_out as u128 * 10u128.pow(18 - config_out.decimals);
+            let amount_in_normalized = Self::mul_div_ceil(env, amount_out_normalized, PRICE_SCALE, exchange_rate.rate);
+            amount_in_normalized.div_ceil(10u128.pow(18 - config_in.decimals))
         } else {
-            PRICE_SCALE // Default to $1
-        }
+            let price_in = Self::get_asset_price_from_oracle(env, token_in)?;
+            let price_out = Self::get_asset_price_from_oracle(env, token_out)?;
+            
+            let value_out = Self::mul_div_ceil(env, amount_out as u128, price_out, 10u128.pow(config_out.decimals));
+            Self::mul_div_ceil(env, value_out, 10u128.pow(config_in.decimals), price_in)
+        };
+        Ok(amount_in as i128)
     }
 
-    fn execute_swap_fixed_rate(
+    /// Output at the pair's ExchangeRate, falling back to oracle cross pricing
+    fn quote_fixed_rate(
         env: &Env,
         token_in: &Address,
         token_out: &Address,
-        amount_in: u128,
-        min_amount_out: u128,
-    ) -> u128 {
-        let key = (symbol_short!("rate"), token_in.clone(), token_out.clone());
+        amount_in: i128,
+    ) -> Result<SwapQuote, HubError> {
+        let key = DataKey::Rate(token_in.clone(), token_out.clone());
+        let config_in = Self::get_asset_config(env, token_in)?;
+        let config_out = Self::get_asset_config(env, token_out)?;
         
         if let Some(exchange_rate) = env.storage().persistent().get::<_, ExchangeRate>(&key) {
-            let config_in = Self::get_asset_config(env, token_in);
-            let config_out = Self::get_asset_config(env, token_out);
+            Self::extend_persistent_ttl(env, &key);
             
             // Adjust for decimals
-            let amount_in_normalized = amount_in * 10u128.pow(18 - config_in.decimals);
-            let amount_out_normalized = (amount_in_normalized * exchange_rate.rate) / PRICE_SCALE;
-            let amount_out = amount_out_normalized / 10u128.pow(18 - config_out.decimals);
+            let amount_in_normalized = amount_in as u128 * 10u128.pow(18 - config_in.decimals);
+            let amount_out_normalized = Self::mul_div(env, amount_in_normalized, exchange_rate.rate, PRICE_SCALE);
+            let amount_out = (amount_out_normalized / 10u128.pow(18 - config_out.decimals)) as i128;
             
-            assert!(amount_out >= min_amount_out, "Insufficient output amount");
-            amount_out
+            Ok(SwapQuote {
+                amount_out,
+                fee: 0,
+                rate: exchange_rate.rate,
+                source: SwapSource::FixedRate,
+                last_update: exchange_rate.last_update,
+            })
         } else {
             // Fallback to oracle-based calculation
-            let price_in = Self::get_asset_price_from_oracle(env, token_in);
-            let price_out = Self::get_asset_price_from_oracle(env, token_out);
-            
-            let config_in = Self::get_asset_config(env, token_in);
-            let config_out = Self::get_asset_config(env, token_out);
+            let price_in = Self::get_asset_price_from_oracle(env, token_in)?;
+            let price_out = Self::get_asset_price_from_oracle(env, token_out)?;
             
-            let value_in = (amount_in * price_in) / 10u128.pow(config_in.decimals);
-            let amount_out = (value_in * 10u128.pow(config_out.decimals)) / price_out;
+            let value_in = Self::asset_value(env, amount_in, price_in, config_in.decimals);
+            let amount_out = Self::asset_amount(env, value_in, price_out, config_out.decimals);
             
-            assert!(amount_out >= min_amount_out, "Insufficient output amount");
-            amount_out
+            Ok(SwapQuote {
+                amount_out,
+                fee: 0,
+                rate: Self::mul_div(env, price_in, PRICE_SCALE, price_out),
+                source: SwapSource::Oracle,
+                last_update: env.ledger().timestamp(),
+            })
         }
     }
 
-    fn check_and_trigger_liquidation_protection(env: &Env, user: &Address) {
-        let position = Self::get_user_position(env.clone(), user.clone());
+    fn check_and_trigger_liquidation_protection(env: &Env, snapshot: &mut Snapshot, user: &Address) -> Result<(), HubError> {
+        let position = Self::load_user_health(env, snapshot, user)?;
         
         if position.health_factor <= position.liquidation_threshold {
-            let protection: LiquidationProtection = env.storage()
-                .persistent()
-                .get(&symbol_short!("liq_prot"))
-                .unwrap();
+            let protection = Self::get_liquidation_protection(env)?;
             
             if protection.enabled {
-                Self::execute_liquidation_protection(env, user);
+                // A held price skips the automatic sale without failing the user's own call
+                match Self::execute_liquidation_protection(env, snapshot, user) {
+                    Ok(_) | Err(HubError::PriceGuarded) => {}
+                    Err(error) => return Err(error),
+                }
             }
         }
+
+        Ok(())
     }
 
-    fn execute_liquidation_protection(env: &Env, user: &Address) -> u128 {
-        let position = Self::get_user_position(env.clone(), user.clone());
-        let protection: LiquidationProtection = env.storage()
-            .persistent()
-            .get(&symbol_short!("liq_prot"))
-            .unwrap();
+    fn get_liquidation_protection(env: &Env) -> Result<LiquidationProtection, HubError> {
+        let key = DataKey::LiquidationProtection;
+        let protection = env.storage().persistent().get(&key).ok_or(HubError::NotInitialized)?;
+        Self::extend_persistent_ttl(env, &key);
+        Ok(protection)
+    }
+
+    fn execute_liquidation_protection(env: &Env, snapshot: &mut Snapshot, user: &Address) -> Result<i128, HubError> {
+        // No forced sale on a price the circuit breaker is holding
+        Self::require_position_unguarded(env, snapshot, &Self::load_user_position(env, user))?;
         
-        let mut total_repaid = 0u128;
+        let position = Self::load_position_in_underlying(env, snapshot, user)?;
+        let protection = Self::get_liquidation_protection(env)?;
+        
+        let mut total_repaid = 0i128;
         
         // Find the largest debt to repay first
         let mut largest_debt_asset = None;
-        let mut largest_debt_amount = 0u128;
+        let mut largest_debt_amount = 0i128;
         
         for (asset, amount) in position.borrowed_assets.iter() {
             if amount > largest_debt_amount {
@@ -669,13 +2310,13 @@ impl StellarDeFiHub {
         
         if let Some(debt_asset) = largest_debt_asset {
             // Calculate repayment amount (max percentage of debt)
-            let max_repay = (largest_debt_amount * protection.max_repay_percentage as u128) / 10000;
+            let max_repay = (largest_debt_amount * protection.max_repay_percentage as i128) / 10000;
             
             // Find collateral to swap for repayment
             let mut collateral_asset = None;
-            let mut collateral_amount = 0u128;
+            let mut collateral_amount = 0i128;
             
-            for (asset, amount) in position.supplied_assets.iter() {
+            for (asset, amount) in position.collateral_assets.iter() {
                 if amount > 0 && asset != debt_asset {
                     collateral_asset = Some(asset);
                     collateral_amount = amount;
@@ -685,30 +2326,35 @@ impl StellarDeFiHub {
             
             if let Some(coll_asset) = collateral_asset {
                 // Calculate how much collateral to swap
-                let debt_price = Self::get_asset_price_from_oracle(env, &debt_asset);
-                let coll_price = Self::get_asset_price_from_oracle(env, &coll_asset);
+                let debt_price = snapshot.risk_price(&debt_asset)?;
+                let coll_price = snapshot.risk_price(&coll_asset)?;
                 
-                let debt_config = Self::get_asset_config(env, &debt_asset);
-                let coll_config = Self::get_asset_config(env, &coll_asset);
+                let debt_config = snapshot.config(&debt_asset)?;
+                let coll_config = snapshot.config(&coll_asset)?;
                 
-                let debt_value = (max_repay * debt_price) / 10u128.pow(debt_config.decimals);
-                let coll_needed = (debt_value * 10u128.pow(coll_config.decimals)) / coll_price;
+                let debt_value = Self::asset_value(env, max_repay, debt_price, debt_config.decimals);
+                let coll_needed = Self::asset_amount(env, debt_value, coll_price, coll_config.decimals);
                 
                 // Add protection fee
-                let coll_with_fee = coll_needed + (coll_needed * protection.protection_fee as u128) / 10000;
+                let coll_with_fee = coll_needed + (coll_needed * protection.protection_fee as i128) / 10000;
                 
                 if coll_with_fee <= collateral_amount {
+                    // Take the user's collateral out of Blend, then swap only those tokens
+                    let shares = Self::submit_to_blend(env, RequestType::WithdrawCollateral, &coll_asset, coll_with_fee)?;
+                    Self::reduce_user_supply_position(env, user, &coll_asset, shares, true)?;
+                    
                     // Execute the protection swap
-                    let swapped_amount = Self::execute_swap_fixed_rate(
-                        env, 
-                        &coll_asset, 
-                        &debt_asset, 
-                        coll_with_fee, 
-                        max_repay
-                    );
+                    let swapped_amount = Self::execute_swap(
+                        env,
+                        &coll_asset,
+                        &debt_asset,
+                        coll_with_fee,
+                        max_repay,
+                        env.ledger().timestamp(),
+                    )?;
                     
                     // Repay debt to Blend
-                    Self::repay_debt_to_blend(env, user, &debt_asset, swapped_amount);
+                    Self::repay_debt_to_blend(env, snapshot, user, &debt_asset, swapped_amount)?;
                     
                     total_repaid = swapped_amount;
                     
@@ -720,73 +2366,65 @@ impl StellarDeFiHub {
             }
         }
         
-        total_repaid
+        Ok(total_repaid)
     }
 
-    fn repay_debt_to_blend(env: &Env, user: &Address, asset: &Address, amount: u128) {
-        let blend_pool: Address = env.storage().instance().get(&symbol_short!("blend")).unwrap();
-        
-        // Approve and repay
-        Self::approve_token(env, asset, &blend_pool, amount);
-        
-        let request = BlendRequest {
-            request_type: 5u32, // Repay
-            address: asset.clone(),
-            amount: amount as i128,
-        };
-        
-        let mut requests = Vec::new(env);
-        requests.push_back(request);
-        
-        env.invoke_contract::<()>(
-            &blend_pool,
-            &symbol_short!("submit"),
-            soroban_sdk::vec![
-                env,
-                env.current_contract_address().into_val(env),
-                env.current_contract_address().into_val(env),
-                env.current_contract_address().into_val(env),
-                requests.into_val(env),
-            ],
-        );
+    fn repay_debt_to_blend(
+        env: &Env,
+        snapshot: &mut Snapshot,
+        user: &Address,
+        asset: &Address,
+        amount: i128,
+    ) -> Result<(), HubError> {
+        let shares = Self::submit_to_blend(env, RequestType::Repay, asset, amount)?;
         
         // Update user position
-        let mut position = Self::get_user_position(env.clone(), user.clone());
+        let mut position = Self::load_user_position(env, user);
         let current_debt = position.borrowed_assets.get(asset.clone()).unwrap_or(0);
-        let new_debt = current_debt.saturating_sub(amount);
+        let new_debt = current_debt - shares;
         
-        if new_debt == 0 {
+        if new_debt <= 0 {
             position.borrowed_assets.remove(asset.clone());
         } else {
             position.borrowed_assets.set(asset.clone(), new_debt);
         }
         
         Self::save_user_position(env, user, &position);
-        Self::update_user_health_factor(env, user);
+        Self::update_asset_totals(env, asset, 0, 0, -shares.min(current_debt));
+        
+        // Reducing debt must not fail on an oracle outage; the stored health factor
+        // is refreshed by the next call that can price the position
+        if let Ok(position) = Self::load_user_health(env, snapshot, user) {
+            Self::save_user_position(env, user, &position);
+        }
+        Ok(())
     }
 
     /// Stake BLEND tokens (only BLEND can be staked)
     pub fn stake_blend(
         env: Env,
         user: Address,
-        amount: u128,
-    ) {
+        amount: i128,
+    ) -> Result<(), HubError> {
         user.require_auth();
-        Self::require_initialized(&env);
+        Self::require_initialized(&env)?;
         
-        assert!(amount > 0, "Amount must be positive");
+        if amount <= 0 {
+            return Err(HubError::InvalidAmount);
+        }
         
         // Get BLEND token address
-        let blend_token = Address::from_string(&String::from_str(&env, "CB22KRA3YZVCNCQI64JQ5WE7UY2VAV7WFLK6A2JN3HEX56T2EDAFO7QF"));
+        let blend_token = Self::get_blend_token(&env)?;
         
         // Transfer BLEND from user
-        Self::transfer_from_user(&env, &blend_token, &user, &env.current_contract_address(), amount);
+        Self::transfer_from_user(&env, &blend_token, &user, &env.current_contract_address(), amount)?;
         
         // Update rewards before changing stake
-        Self::update_blend_rewards(&env, &user);
+        Self::update_blend_rewards(&env, &user)?;
         
         // Update staking position
-        let mut position = Self::get_user_position(env.clone(), user.clone());
+        let mut position = Self::load_user_position(&env, &user);
+        Self::claim_staker_fees(&env, &user, position.staked_blend)?;
         position.staked_blend += amount;
         Self::save_user_position(&env, &user, &position);
         
@@ -797,42 +2435,55 @@ impl StellarDeFiHub {
             (symbol_short!("stake_bld"), &user),
             amount
         );
+
+        Ok(())
     }
 
-    /// Unstake BLEND and claim rewards
+    /// Unstake BLEND and claim the rewards the hub holds BLEND to pay
     pub fn unstake_blend(
         env: Env,
         user: Address,
-        amount: u128,
-    ) -> u128 {
+        amount: i128,
+    ) -> Result<i128, HubError> {
         user.require_auth();
-        Self::require_initialized(&env);
+        Self::require_initialized(&env)?;
         
-        let mut position = Self::get_user_position(env.clone(), user.clone());
-        assert!(position.staked_blend >= amount, "Insufficient staked balance");
+        // Zero claims rewards and fees without unstaking
+        if amount < 0 {
+            return Err(HubError::InvalidAmount);
+        }
         
         // Update rewards before unstaking
-        Self::update_blend_rewards(&env, &user);
+        Self::update_blend_rewards(&env, &user)?;
+        
+        let mut position = Self::load_user_position(&env, &user);
+        if position.staked_blend < amount {
+            return Err(HubError::InsufficientBalance);
+        }
+        Self::claim_staker_fees(&env, &user, position.staked_blend)?;
         
-        // Get rewards to claim
-        let rewards = position.rewards_earned;
+        // Rewards have no dedicated reserve, so only BLND beyond fees and stakes is
+        // paid out; the rest stays owed until the hub holds enough
+        let blend_token = Self::get_blend_token(&env)?;
+        let rewards = position
+            .rewards_earned
+            .min(Self::available_balance(&env, &blend_token)?.max(0));
         
         // Update positions
         position.staked_blend -= amount;
-        position.rewards_earned = 0;
+        position.rewards_earned -= rewards;
         Self::save_user_position(&env, &user, &position);
         
         // Update staking pool
         Self::update_blend_staking_pool(&env, amount, false);
         
-        let blend_token = Address::from_string(&String::from_str(&env, "CB22KRA3YZVCNCQI64JQ5WE7UY2VAV7WFLK6A2JN3HEX56T2EDAFO7QF"));
-        
+
         // Transfer staked BLEND back to user
-        Self::transfer_to_user(&env, &blend_token, &user, amount);
+        Self::transfer_to_user(&env, &blend_token, &user, amount)?;
         
-        // Transfer rewards (in BLEND or protocol fees)
+        // Transfer BLEND staking rewards
         if rewards > 0 {
-            Self::transfer_rewards(&env, &user, rewards);
+            Self::transfer_rewards(&env, &user, rewards)?;
         }
         
         env.events().publish(
@@ -840,7 +2491,100 @@ impl StellarDeFiHub {
             (amount, rewards)
         );
         
-        rewards
+        Ok(rewards)
+    }
+
+    /// Set how protocol fees are split between stakers, treasury and backstop (admin only)
+    pub fn set_fee_split(env: Env, admin: Address, split: FeeSplit) -> Result<(), HubError> {
+        admin.require_auth();
+        Self::require_admin(&env, &admin)?;
+        
+        if split.stakers_bps as u64 + split.treasury_bps as u64 + split.backstop_bps as u64 != 10000 {
+            return Err(HubError::InvalidAmount);
+        }
+        
+        env.storage().instance().set(&DataKey::FeeSplit, &split);
+        Self::extend_instance_ttl(&env);
+        
+        env.events().publish(
+            (symbol_short!("fee_split"), &admin),
+            (split.stakers_bps, split.treasury_bps, split.backstop_bps)
+        );
+
+        Ok(())
+    }
+
+    /// Get the protocol fee split; all fees go to stakers until one is set
+    pub fn get_fee_split(env: Env) -> FeeSplit {
+        Self::load_fee_split(&env)
+    }
+
+    /// Protocol fees held by the hub for each token that has any
+    pub fn get_accrued_fees(env: Env) -> Map<Address, AccruedFees> {
+        let mut fees = Map::new(&env);
+        for token in Self::get_asset_list(&env).iter() {
+            let accrued = Self::load_accrued_fees(&env, &token);
+            if accrued.stakers != 0 || accrued.treasury != 0 || accrued.backstop != 0 {
+                fees.set(token, accrued);
+            }
+        }
+        fees
+    }
+
+    /// Withdraw treasury fees in a token (admin only)
+    pub fn withdraw_fees(
+        env: Env,
+        admin: Address,
+        token: Address,
+        to: Address,
+        amount: i128,
+    ) -> Result<(), HubError> {
+        admin.require_auth();
+        Self::require_admin(&env, &admin)?;
+        
+        if amount <= 0 {
+            return Err(HubError::InvalidAmount);
+        }
+        let mut fees = Self::load_accrued_fees(&env, &token);
+        if fees.treasury < amount {
+            return Err(HubError::InsufficientBalance);
+        }
+        
+        fees.treasury -= amount;
+        Self::save_accrued_fees(&env, &token, &fees);
+        Self::transfer_to_user(&env, &token, &to, amount)?;
+        
+        env.events().publish(
+            (symbol_short!("fees_out"), &token),
+            (to, amount)
+        );
+
+        Ok(())
+    }
+
+    /// Credit the backstop's share of fees in a token to the Blend pool's backstop (anyone can call)
+    pub fn send_backstop_fees(env: Env, token: Address) -> Result<i128, HubError> {
+        Self::require_initialized(&env)?;
+        
+        let mut fees = Self::load_accrued_fees(&env, &token);
+        let amount = fees.backstop;
+        if amount > 0 {
+            fees.backstop = 0;
+            Self::save_accrued_fees(&env, &token, &fees);
+            
+            // The pool books tokens it receives unaccounted as backstop credit, which
+            // its backstop collects through interest auctions
+            let blend_pool = Self::get_blend_pool(&env)?;
+            Self::transfer_to_user(&env, &token, &blend_pool, amount)?;
+            BlendPoolClient::new(&env, &blend_pool).gulp(&token);
+            
+            env.events().publish(
+                (symbol_short!("bstp_fees"), &token),
+                (blend_pool, amount)
+            );
+        }
+        
+        Ok(amount)
     }
 
     /// Emergency withdraw (admin only) - for frozen pools
@@ -849,41 +2593,28 @@ impl StellarDeFiHub {
         admin: Address,
         user: Address,
         asset: Address,
-        amount: u128,
-    ) {
+        amount: i128,
+    ) -> Result<(), HubError> {
         admin.require_auth();
-        Self::require_admin(&env, &admin);
-        
-        // Emergency function to help users when pools are frozen
-        let blend_pool: Address = env.storage().instance().get(&symbol_short!("blend")).unwrap();
+        Self::require_admin(&env, &admin)?;
         
-        let request = BlendRequest {
-            request_type: 3u32, // Withdraw Collateral
-            address: asset.clone(),
-            amount: amount as i128,
-        };
+        if amount <= 0 {
+            return Err(HubError::InvalidAmount);
+        }
         
-        let mut requests = Vec::new(&env);
-        requests.push_back(request);
-        
-        env.invoke_contract::<()>(
-            &blend_pool,
-            &symbol_short!("submit"),
-            soroban_sdk::vec![
-                &env,
-                env.current_contract_address().into_val(&env),
-                env.current_contract_address().into_val(&env),
-                env.current_contract_address().into_val(&env),
-                requests.into_val(&env),
-            ],
-        );
+        // Emergency function to help users when pools are frozen
+        let position = Self::load_user_position(&env, &user);
+        let (lend_amount, collateral_amount) = Self::split_withdrawal(&env, &mut Snapshot::new(&env), &position, &asset, amount)?;
+        Self::withdraw_user_supply(&env, &user, &asset, lend_amount, collateral_amount)?;
         
-        Self::transfer_to_user(&env, &asset, &user, amount);
+        Self::transfer_to_user(&env, &asset, &user, amount)?;
         
         env.events().publish(
             (symbol_short!("emergency"), &admin),
             (user, asset, amount)
         );
+
+        Ok(())
     }
 
     /// Liquidate unhealthy position (anyone can call)
@@ -893,90 +2624,100 @@ impl StellarDeFiHub {
         borrower: Address,
         debt_asset: Address,
         collateral_asset: Address,
-        debt_to_cover: u128,
-    ) -> u128 {
+        debt_to_cover: i128,
+    ) -> Result<i128, HubError> {
         liquidator.require_auth();
-        Self::require_initialized(&env);
+        Self::require_initialized(&env)?;
+        
+        if debt_to_cover <= 0 {
+            return Err(HubError::InvalidAmount);
+        }
         
-        let position = Self::get_user_position(env.clone(), borrower.clone());
-        assert!(position.health_factor < LIQUIDATION_THRESHOLD, "Position is healthy");
+        // The health factor is priced from every asset in the position
+        let mut snapshot = Snapshot::new(&env);
+        Self::require_price_unguarded(&env, &mut snapshot, &debt_asset)?;
+        Self::require_price_unguarded(&env, &mut snapshot, &collateral_asset)?;
+        Self::require_position_unguarded(&env, &mut snapshot, &Self::load_user_position(&env, &borrower))?;
+        
+        let position = Self::load_position_in_underlying(&env, &mut snapshot, &borrower)?;
+        if position.health_factor >= LIQUIDATION_THRESHOLD {
+            return Err(HubError::PositionHealthy);
+        }
         
         let debt_amount = position.borrowed_assets.get(debt_asset.clone()).unwrap_or(0);
-        assert!(debt_amount >= debt_to_cover, "Debt amount too low");
+        if debt_amount < debt_to_cover {
+            return Err(HubError::InsufficientDebt);
+        }
         
-        let collateral_amount = position.supplied_assets.get(collateral_asset.clone()).unwrap_or(0);
-        assert!(collateral_amount > 0, "No collateral available");
+        let collateral_amount = position.collateral_assets.get(collateral_asset.clone()).unwrap_or(0);
+        if collateral_amount == 0 {
+            return Err(HubError::InsufficientCollateral);
+        }
         
         // Calculate liquidation bonus
-        let collateral_config = Self::get_asset_config(&env, &collateral_asset);
+        let collateral_config = snapshot.config(&collateral_asset)?;
         let liquidation_bonus = collateral_config.liquidation_bonus;
         
         // Calculate collateral to seize
-        let debt_price = Self::get_asset_price_from_oracle(&env, &debt_asset);
-        let collateral_price = Self::get_asset_price_from_oracle(&env, &collateral_asset);
+        let debt_price = snapshot.risk_price(&debt_asset)?;
+        let collateral_price = snapshot.risk_price(&collateral_asset)?;
         
-        let debt_config = Self::get_asset_config(&env, &debt_asset);
+        let debt_config = snapshot.config(&debt_asset)?;
         
-        let debt_value = (debt_to_cover * debt_price) / 10u128.pow(debt_config.decimals);
+        let debt_value = Self::asset_value(&env, debt_to_cover, debt_price, debt_config.decimals);
         let collateral_value_needed = debt_value + (debt_value * liquidation_bonus as u128) / 10000;
-        let collateral_to_seize = (collateral_value_needed * 10u128.pow(collateral_config.decimals)) / collateral_price;
+        let collateral_to_seize = Self::asset_amount(&env, collateral_value_needed, collateral_price, collateral_config.decimals);
         
-        assert!(collateral_to_seize <= collateral_amount, "Insufficient collateral");
+        if collateral_to_seize > collateral_amount {
+            return Err(HubError::InsufficientCollateral);
+        }
         
         // Transfer debt payment from liquidator
-        Self::transfer_from_user(&env, &debt_asset, &liquidator, &env.current_contract_address(), debt_to_cover);
+        Self::transfer_from_user(&env, &debt_asset, &liquidator, &env.current_contract_address(), debt_to_cover)?;
         
         // Repay debt to Blend
-        Self::repay_debt_to_blend(&env, &borrower, &debt_asset, debt_to_cover);
-        
-        // Transfer collateral to liquidator
-        Self::transfer_to_user(&env, &collateral_asset, &liquidator, collateral_to_seize);
-        
-        // Update borrower's position
-        let mut borrower_position = Self::get_user_position(env.clone(), borrower.clone());
-        let current_collateral = borrower_position.supplied_assets.get(collateral_asset.clone()).unwrap_or(0);
-        let new_collateral = current_collateral.saturating_sub(collateral_to_seize);
+        Self::repay_debt_to_blend(&env, &mut snapshot, &borrower, &debt_asset, debt_to_cover)?;
         
-        if new_collateral == 0 {
-            borrower_position.supplied_assets.remove(collateral_asset.clone());
-        } else {
-            borrower_position.supplied_assets.set(collateral_asset.clone(), new_collateral);
-        }
+        // Withdraw the seized collateral from Blend and update borrower's position
+        let shares = Self::submit_to_blend(&env, RequestType::WithdrawCollateral, &collateral_asset, collateral_to_seize)?;
+        Self::reduce_user_supply_position(&env, &borrower, &collateral_asset, shares, true)?;
+        Self::update_user_health_factor(&env, &mut snapshot, &borrower)?;
         
-        Self::save_user_position(&env, &borrower, &borrower_position);
-        Self::update_user_health_factor(&env, &borrower);
+        // Transfer collateral to liquidator
+        Self::transfer_to_user(&env, &collateral_asset, &liquidator, collateral_to_seize)?;
         
         env.events().publish(
             (symbol_short!("liquidate"), &liquidator),
             (borrower, debt_asset, collateral_asset, debt_to_cover, collateral_to_seize)
         );
         
-        collateral_to_seize
+        Ok(collateral_to_seize)
     }
 
-    fn update_user_health_factor(env: &Env, user: &Address) {
-        let mut position = Self::get_user_position(env.clone(), user.clone());
-        position.health_factor = Self::calculate_health_factor(env, &position);
+    fn update_user_health_factor(env: &Env, snapshot: &mut Snapshot, user: &Address) -> Result<(), HubError> {
+        let position = Self::load_user_health(env, snapshot, user)?;
         Self::save_user_position(env, user, &position);
+        Ok(())
     }
 
-    fn update_blend_rewards(env: &Env, user: &Address) {
-        let mut position = Self::get_user_position(env.clone(), user.clone());
+    fn update_blend_rewards(env: &Env, user: &Address) -> Result<(), HubError> {
+        let mut position = Self::load_user_position(env, user);
         let current_time = env.ledger().timestamp();
         
         if position.staked_blend > 0 {
             let time_elapsed = current_time - position.last_reward_update;
             let daily_rate = 100; // 1% daily APY as example
-            let rewards = (position.staked_blend * daily_rate * time_elapsed as u128) / (10000 * SECONDS_PER_DAY as u128);
+            let rewards = (position.staked_blend * daily_rate * time_elapsed as i128) / (10000 * SECONDS_PER_DAY as i128);
             position.rewards_earned += rewards;
         }
         
         position.last_reward_update = current_time;
         Self::save_user_position(env, user, &position);
+        Ok(())
     }
 
-    fn update_blend_staking_pool(env: &Env, amount: u128, is_stake: bool) {
-        let key = symbol_short!("bld_pool");
+    fn update_blend_staking_pool(env: &Env, amount: i128, is_stake: bool) {
+        let key = DataKey::BlendStakingPool;
         let mut pool = env.storage()
             .persistent()
             .get(&key)
@@ -991,65 +2732,285 @@ impl StellarDeFiHub {
         if is_stake {
             pool.total_staked += amount;
         } else {
-            pool.total_staked = pool.total_staked.saturating_sub(amount);
+            pool.total_staked = (pool.total_staked - amount).max(0);
         }
         
         pool.last_update_time = env.ledger().timestamp();
         env.storage().persistent().set(&key, &pool);
+        Self::extend_persistent_ttl(env, &key);
     }
 
-    fn transfer_from_user(env: &Env, token: &Address, user: &Address, to: &Address, amount: u128) {
-        env.invoke_contract::<()>(
-            token,
-            &symbol_short!("trans_frm"),
-            soroban_sdk::vec![env, user.into_val(env), to.into_val(env), amount.into_val(env)],
-        );
+    fn transfer_from_user(env: &Env, token: &Address, user: &Address, to: &Address, amount: i128) -> Result<(), HubError> {
+        // Authorized by the user's signature on the outer call
+        match token::Client::new(env, token).try_transfer(user, to, &amount) {
+            Ok(Ok(())) => Ok(()),
+            _ => Err(HubError::InsufficientBalance),
+        }
     }
 
-    fn transfer_to_user(env: &Env, token: &Address, user: &Address, amount: u128) {
-        env.invoke_contract::<()>(
-            token,
-            &symbol_short!("transfer"),
-            soroban_sdk::vec![env, user.into_val(env), amount.into_val(env)],
-        );
+    fn transfer_to_user(env: &Env, token: &Address, user: &Address, amount: i128) -> Result<(), HubError> {
+        match token::Client::new(env, token).try_transfer(&env.current_contract_address(), user, &amount) {
+            Ok(Ok(())) => Ok(()),
+            _ => Err(HubError::InsufficientBalance),
+        }
     }
 
-    fn approve_token(env: &Env, token: &Address, spender: &Address, amount: u128) {
-        env.invoke_contract::<()>(
-            token,
-            &symbol_short!("approve"),
-            soroban_sdk::vec![env, spender.into_val(env), amount.into_val(env)],
-        );
+    fn load_fee_split(env: &Env) -> FeeSplit {
+        env.storage().instance().get(&DataKey::FeeSplit).unwrap_or(FeeSplit {
+            stakers_bps: 10000,
+            treasury_bps: 0,
+            backstop_bps: 0,
+        })
+    }
+
+    fn total_staked(env: &Env) -> i128 {
+        env.storage()
+            .persistent()
+            .get::<_, BlendStakingPool>(&DataKey::BlendStakingPool)
+            .map_or(0, |pool| pool.total_staked)
+    }
+
+    /// Hub balance of a token less booked fees and staked BLND, which swaps may not pay out
+    fn available_balance(env: &Env, token: &Address) -> Result<i128, HubError> {
+        let fees = Self::load_accrued_fees(env, token);
+        let mut reserved = fees.stakers + fees.treasury + fees.backstop;
+        if *token == Self::get_blend_token(env)? {
+            reserved += Self::total_staked(env);
+        }
+        let balance = token::Client::new(env, token).balance(&env.current_contract_address());
+        Ok(balance - reserved)
+    }
+
+    fn load_accrued_fees(env: &Env, token: &Address) -> AccruedFees {
+        let key = DataKey::Fees(token.clone());
+        match env.storage().persistent().get(&key) {
+            Some(fees) => {
+                Self::extend_persistent_ttl(env, &key);
+                fees
+            }
+            None => AccruedFees { stakers: 0, treasury: 0, backstop: 0 },
+        }
+    }
+
+    fn save_accrued_fees(env: &Env, token: &Address, fees: &AccruedFees) {
+        let key = DataKey::Fees(token.clone());
+        env.storage().persistent().set(&key, fees);
+        Self::extend_persistent_ttl(env, &key);
+    }
+
+    fn get_fee_index(env: &Env, token: &Address) -> u128 {
+        env.storage().persistent().get(&DataKey::FeeIndex(token.clone())).unwrap_or(0)
+    }
+
+    /// Split a protocol fee between destinations; the stakers' share is spread over
+    /// the current stake, or goes to the treasury if nothing is staked
+    fn accrue_fee(env: &Env, token: &Address, amount: i128) {
+        if amount <= 0 {
+            return;
+        }
+        let split = Self::load_fee_split(env);
+        let mut fees = Self::load_accrued_fees(env, token);
+        
+        let stakers = amount * split.stakers_bps as i128 / 10000;
+        let backstop = amount * split.backstop_bps as i128 / 10000;
+        let mut treasury = amount - stakers - backstop;
+        
+        let total_staked = Self::total_staked(env);
+        if total_staked > 0 {
+            let key = DataKey::FeeIndex(token.clone());
+            let index = Self::get_fee_index(env, token)
+                + Self::mul_div(env, stakers as u128, PRICE_SCALE, total_staked as u128);
+            env.storage().persistent().set(&key, &index);
+            Self::extend_persistent_ttl(env, &key);
+            fees.stakers += stakers;
+        } else {
+            treasury += stakers;
+        }
+        
+        fees.treasury += treasury;
+        fees.backstop += backstop;
+        Self::save_accrued_fees(env, token, &fees);
     }
 
-    fn add_to_reward_pool(env: &Env, amount: u128) {
-        let key = symbol_short!("rewards");
-        let current: u128 = env.storage().persistent().get(&key).unwrap_or(0);
-        env.storage().persistent().set(&key, &(current + amount));
+    /// Pay a staker's share of fees in every token since their last claim
+    fn claim_staker_fees(env: &Env, user: &Address, staked: i128) -> Result<(), HubError> {
+        for token in Self::get_asset_list(env).iter() {
+            let index = Self::get_fee_index(env, &token);
+            if index == 0 {
+                continue;
+            }
+            let key = DataKey::FeeCheckpoint(user.clone(), token.clone());
+            let checkpoint: u128 = env.storage().persistent().get(&key).unwrap_or(0);
+            if checkpoint == index {
+                continue;
+            }
+            
+            let owed = Self::mul_div(env, staked.max(0) as u128, index - checkpoint, PRICE_SCALE) as i128;
+            if owed > 0 {
+                let mut fees = Self::load_accrued_fees(env, &token);
+                fees.stakers -= owed;
+                Self::save_accrued_fees(env, &token, &fees);
+                Self::transfer_to_user(env, &token, user, owed)?;
+                
+                env.events().publish(
+                    (symbol_short!("stk_fees"), user),
+                    (token.clone(), owed)
+                );
+            }
+            env.storage().persistent().set(&key, &index);
+            Self::extend_persistent_ttl(env, &key);
+        }
+        Ok(())
+    }
+
+    fn transfer_rewards(env: &Env, user: &Address, amount: i128) -> Result<(), HubError> {
+        // BLEND staking rewards; protocol fees are paid by claim_staker_fees
+        let blend_token = Self::get_blend_token(env)?;
+        Self::transfer_to_user(env, &blend_token, user, amount)?;
+        Ok(())
     }
 
-    fn transfer_rewards(env: &Env, user: &Address, amount: u128) {
-        // Transfer from reward pool to user
-        // Could be BLEND tokens or other protocol fee tokens
-        let blend_token = Address::from_string(&String::from_str(env, "CB22KRA3YZVCNCQI64JQ5WE7UY2VAV7WFLK6A2JN3HEX56T2EDAFO7QF"));
-        Self::transfer_to_user(env, &blend_token, user, amount);
+    fn update_user_supply_position(
+        env: &Env,
+        user: &Address,
+        asset: &Address,
+        shares: i128,
+        as_collateral: bool,
+    ) -> Result<(), HubError> {
+        let mut position = Self::load_user_position(env, user);
+        let balances = if as_collateral { &mut position.collateral_assets } else { &mut position.supplied_assets };
+        let current = balances.get(asset.clone()).unwrap_or(0);
+        balances.set(asset.clone(), current + shares);
+        Self::save_user_position(env, user, &position);
+
+        if as_collateral {
+            Self::update_asset_totals(env, asset, shares, 0, 0);
+        } else {
+            Self::update_asset_totals(env, asset, 0, shares, 0);
+        }
+        Ok(())
     }
 
-    fn update_user_supply_position(env: &Env, user: &Address, asset: &Address, amount: u128) {
-        let mut position = Self::get_user_position(env.clone(), user.clone());
-        let current = position.supplied_assets.get(asset.clone()).unwrap_or(0);
-        position.supplied_assets.set(asset.clone(), current + amount);
+    fn reduce_user_supply_position(
+        env: &Env,
+        user: &Address,
+        asset: &Address,
+        shares: i128,
+        from_collateral: bool,
+    ) -> Result<(), HubError> {
+        let mut position = Self::load_user_position(env, user);
+        let balances = if from_collateral { &mut position.collateral_assets } else { &mut position.supplied_assets };
+        let current = balances.get(asset.clone()).unwrap_or(0);
+        let new_supply = current - shares;
+
+        if new_supply <= 0 {
+            balances.remove(asset.clone());
+        } else {
+            balances.set(asset.clone(), new_supply);
+        }
+
         Self::save_user_position(env, user, &position);
+
+        let removed = shares.min(current);
+        if from_collateral {
+            Self::update_asset_totals(env, asset, -removed, 0, 0);
+        } else {
+            Self::update_asset_totals(env, asset, 0, -removed, 0);
+        }
+        Ok(())
+    }
+
+    /// Split a withdrawal into lend-only and collateral parts, lend-only first
+    fn split_withdrawal(
+        env: &Env,
+        snapshot: &mut Snapshot,
+        position: &UserPosition,
+        asset: &Address,
+        amount: i128,
+    ) -> Result<(i128, i128), HubError> {
+        let b_rate = snapshot.reserve(asset)?.data.b_rate;
+        let supplied_shares = position.supplied_assets.get(asset.clone()).unwrap_or(0);
+        let collateral_shares = position.collateral_assets.get(asset.clone()).unwrap_or(0);
+
+        let lend_amount = Self::shares_to_amount(env, supplied_shares, b_rate, false).min(amount);
+        let collateral_amount = amount - lend_amount;
+        if Self::shares_to_amount(env, collateral_shares, b_rate, false) < collateral_amount {
+            return Err(HubError::InsufficientBalance);
+        }
+        Ok((lend_amount, collateral_amount))
+    }
+
+    /// Withdraw a user's lend-only and collateral balances from Blend to the hub
+    fn withdraw_user_supply(
+        env: &Env,
+        user: &Address,
+        asset: &Address,
+        lend_amount: i128,
+        collateral_amount: i128,
+    ) -> Result<(), HubError> {
+        if lend_amount > 0 {
+            let shares = Self::submit_to_blend(env, RequestType::Withdraw, asset, lend_amount)?;
+            Self::reduce_user_supply_position(env, user, asset, shares, false)?;
+        }
+        if collateral_amount > 0 {
+            let shares = Self::submit_to_blend(env, RequestType::WithdrawCollateral, asset, collateral_amount)?;
+            Self::reduce_user_supply_position(env, user, asset, shares, true)?;
+        }
+        Ok(())
     }
 
-    fn update_user_borrow_position(env: &Env, user: &Address, asset: &Address, amount: u128) {
-        let mut position = Self::get_user_position(env.clone(), user.clone());
+    fn update_user_borrow_position(env: &Env, user: &Address, asset: &Address, shares: i128) -> Result<(), HubError> {
+        let mut position = Self::load_user_position(env, user);
         let current = position.borrowed_assets.get(asset.clone()).unwrap_or(0);
-        position.borrowed_assets.set(asset.clone(), current + amount);
+        position.borrowed_assets.set(asset.clone(), current + shares);
         Self::save_user_position(env, user, &position);
+        Self::update_asset_totals(env, asset, 0, 0, shares);
+        Ok(())
+    }
+
+    /// Stored position in bToken/dToken shares
+    fn load_user_position(env: &Env, user: &Address) -> UserPosition {
+        let key = DataKey::Position(user.clone());
+        let position = match env.storage().persistent().get::<_, UserPosition>(&key) {
+            Some(position) => {
+                Self::extend_persistent_ttl(env, &key);
+                position
+            }
+            None => UserPosition {
+                collateral_assets: Map::new(env),
+                supplied_assets: Map::new(env),
+                borrowed_assets: Map::new(env),
+                staked_blend: 0,
+                rewards_earned: 0,
+                last_reward_update: env.ledger().timestamp(),
+                health_factor: HEALTH_FACTOR_SCALE, // Default to 1.0
+                liquidation_threshold: LIQUIDATION_THRESHOLD,
+            },
+        };
+        Self::extend_instance_ttl(env);
+        position
+    }
+
+    /// Stored position in bToken/dToken shares with current health factor
+    fn load_user_health(env: &Env, snapshot: &mut Snapshot, user: &Address) -> Result<UserPosition, HubError> {
+        let mut position = Self::load_user_position(env, user);
+        position.health_factor = Self::calculate_health_factor(env, snapshot, &position)?;
+        Ok(position)
+    }
+
+    /// Position in underlying amounts with current health factor
+    fn load_position_in_underlying(env: &Env, snapshot: &mut Snapshot, user: &Address) -> Result<UserPosition, HubError> {
+        let position = Self::load_user_health(env, snapshot, user)?;
+        Self::position_in_underlying(env, snapshot, &position)
     }
 
     fn save_user_position(env: &Env, user: &Address, position: &UserPosition) {
-        env.storage().persistent().set(&(symbol_short!("pos"), user.clone()), position);
+        let key = DataKey::Position(user.clone());
+        env.storage().persistent().set(&key, position);
+        Self::extend_persistent_ttl(env, &key);
+        Self::extend_instance_ttl(env);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test;
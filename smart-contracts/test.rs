@@ -0,0 +1,1569 @@
+#![cfg(test)]
+
+use super::*;
+use crate::blend::{BlendPoolInterface, PoolConfig, Reserve, ReserveConfig, ReserveData, RATE_SCALAR};
+use crate::oracle::{self, PriceData, PriceFeedInterface};
+use crate::soroswap::SoroswapRouterInterface;
+use soroban_sdk::testutils::storage::{Instance as _, Persistent as _};
+use soroban_sdk::testutils::{Address as _, Events, Ledger};
+use soroban_sdk::{token, vec, Env};
+
+// ===================
+// MOCK CONTRACTS
+// ===================
+
+// Minimal Blend pool: moves tokens for each request and tracks bToken/dToken
+// positions by reserve index at admin-set rates
+#[contract]
+pub struct MockBlendPool;
+
+#[contractimpl]
+impl MockBlendPool {
+    pub fn set_status(env: Env, status: u32) {
+        env.storage().instance().set(&symbol_short!("status"), &status);
+    }
+
+    pub fn set_reserve(env: Env, asset: Address, index: u32) {
+        env.storage().persistent().set(&asset, &index);
+    }
+
+    pub fn set_rates(env: Env, asset: Address, b_rate: i128, d_rate: i128) {
+        env.storage().persistent().set(&(asset, symbol_short!("rates")), &(b_rate, d_rate));
+    }
+
+    pub fn set_factors(env: Env, asset: Address, c_factor: u32, l_factor: u32) {
+        env.storage().persistent().set(&(asset, symbol_short!("factors")), &(c_factor, l_factor));
+    }
+
+    // Overwrite an account's positions to simulate drift
+    pub fn set_positions(env: Env, address: Address, positions: Positions) {
+        env.storage().persistent().set(&address, &positions);
+    }
+
+    // Count the pool's current balance of an asset as supplied liquidity
+    pub fn sync_liquidity(env: Env, asset: Address) {
+        let balance = token::Client::new(&env, &asset).balance(&env.current_contract_address());
+        env.storage().persistent().set(&(asset, symbol_short!("held")), &balance);
+    }
+
+    // A broken pool moves tokens but leaves positions untouched
+    pub fn set_broken(env: Env, broken: bool) {
+        env.storage().instance().set(&symbol_short!("broken"), &broken);
+    }
+}
+
+#[contractimpl]
+impl BlendPoolInterface for MockBlendPool {
+    fn submit(env: Env, from: Address, spender: Address, to: Address, requests: Vec<Request>) -> Positions {
+        let pool = env.current_contract_address();
+        let broken: bool = env.storage().instance().get(&symbol_short!("broken")).unwrap_or(false);
+        let mut positions = Self::get_positions(env.clone(), from.clone());
+
+        for request in requests.iter() {
+            let token = token::Client::new(&env, &request.address);
+            let reserve = Self::get_reserve(env.clone(), request.address.clone());
+            let index = reserve.config.index;
+            // Rounds in the pool's favour, like Blend
+            let down = |rate: i128| request.amount * RATE_SCALAR / rate;
+            let up = |rate: i128| (request.amount * RATE_SCALAR + rate - 1) / rate;
+            let (b_rate, d_rate) = (reserve.data.b_rate, reserve.data.d_rate);
+            let (balances, delta) = match request.request_type {
+                0 => (&mut positions.supply, down(b_rate)),
+                1 => (&mut positions.supply, -up(b_rate)),
+                2 => (&mut positions.collateral, down(b_rate)),
+                3 => (&mut positions.collateral, -up(b_rate)),
+                4 => (&mut positions.liabilities, up(d_rate)),
+                5 => (&mut positions.liabilities, -down(d_rate)),
+                _ => panic!("unsupported request type"),
+            };
+            let balance = balances.get(index).unwrap_or(0) + delta;
+            if balance < 0 {
+                panic!("balance below zero");
+            }
+            if !broken {
+                balances.set(index, balance);
+            }
+
+            let inflow = match request.request_type {
+                // Supply / supply collateral / repay pull from the spender
+                0 | 2 | 5 => {
+                    token.transfer(&spender, &pool, &request.amount);
+                    request.amount
+                }
+                // Withdraw / withdraw collateral / borrow pay out to `to`
+                _ => {
+                    token.transfer(&pool, &to, &request.amount);
+                    -request.amount
+                }
+            };
+            let held_key = (request.address.clone(), symbol_short!("held"));
+            let held: i128 = env.storage().persistent().get(&held_key).unwrap_or(0);
+            env.storage().persistent().set(&held_key, &(held + inflow));
+        }
+
+        env.storage().persistent().set(&from, &positions);
+        positions
+    }
+
+    fn get_positions(env: Env, address: Address) -> Positions {
+        env.storage().persistent().get(&address).unwrap_or(Positions {
+            liabilities: Map::new(&env),
+            collateral: Map::new(&env),
+            supply: Map::new(&env),
+        })
+    }
+
+    fn get_reserve(env: Env, asset: Address) -> Reserve {
+        let index: u32 = env.storage().persistent().get(&asset).expect("unknown reserve");
+        let (b_rate, d_rate): (i128, i128) = env
+            .storage()
+            .persistent()
+            .get(&(asset.clone(), symbol_short!("rates")))
+            .unwrap_or((RATE_SCALAR, RATE_SCALAR));
+        let (c_factor, l_factor): (u32, u32) = env
+            .storage()
+            .persistent()
+            .get(&(asset.clone(), symbol_short!("factors")))
+            .unwrap_or((9_000_000, 9_000_000));
+        let backstop_credit: i128 = env
+            .storage()
+            .persistent()
+            .get(&(asset.clone(), symbol_short!("credit")))
+            .unwrap_or(0);
+        Reserve {
+            asset,
+            config: ReserveConfig {
+                index,
+                decimals: 7,
+                c_factor,
+                l_factor,
+                util: 8_000_000,
+                max_util: 9_500_000,
+                r_base: 100_000,
+                r_one: 500_000,
+                r_two: 5_000_000,
+                r_three: 15_000_000,
+                reactivity: 200,
+                supply_cap: i128::MAX,
+                enabled: true,
+            },
+            data: ReserveData {
+                d_rate,
+                b_rate,
+                ir_mod: 10_000_000,
+                b_supply: 0,
+                d_supply: 0,
+                backstop_credit,
+                last_time: env.ledger().timestamp(),
+            },
+            scalar: 10_000_000,
+        }
+    }
+
+    // Tokens held beyond what supply and repay requests brought in become backstop credit
+    fn gulp(env: Env, asset: Address) -> i128 {
+        let accounted: i128 = env.storage().persistent().get(&(asset.clone(), symbol_short!("held"))).unwrap_or(0);
+        let credit_key = (asset.clone(), symbol_short!("credit"));
+        let credit: i128 = env.storage().persistent().get(&credit_key).unwrap_or(0);
+        let balance = token::Client::new(&env, &asset).balance(&env.current_contract_address());
+        let delta = balance - accounted - credit;
+        if delta <= 0 {
+            return 0;
+        }
+        env.storage().persistent().set(&credit_key, &(credit + delta));
+        delta
+    }
+
+    fn get_config(env: Env) -> PoolConfig {
+        PoolConfig {
+            oracle: env.current_contract_address(),
+            min_collateral: 0,
+            bstop_rate: 1_000_000,
+            status: env.storage().instance().get(&symbol_short!("status")).unwrap_or(1),
+            max_positions: 12,
+        }
+    }
+}
+
+// SEP-40 price feed with 14 decimals, like Reflector; prices are set scaled
+// by 1e18 and stamped with the current ledger time
+#[contract]
+pub struct MockOracle;
+
+#[contractimpl]
+impl MockOracle {
+    pub fn set_price(env: Env, asset: Address, price: u128) {
+        let price_data = PriceData {
+            price: (price / 10_000) as i128,
+            timestamp: env.ledger().timestamp(),
+        };
+        env.storage().persistent().set(&asset, &price_data);
+    }
+
+    pub fn clear_price(env: Env, asset: Address) {
+        env.storage().persistent().remove(&asset);
+    }
+}
+
+#[contractimpl]
+impl PriceFeedInterface for MockOracle {
+    fn lastprice(env: Env, asset: oracle::Asset) -> Option<PriceData> {
+        match asset {
+            oracle::Asset::Stellar(address) => env.storage().persistent().get(&address),
+            oracle::Asset::Other(_) => None,
+        }
+    }
+
+    fn decimals(_env: Env) -> u32 {
+        14
+    }
+
+    fn resolution(_env: Env) -> u32 {
+        300
+    }
+}
+
+// Token reporting more decimals than the hub can normalize; in its own module
+// because the oracle mock also exports `decimals`
+mod wide_token {
+    use soroban_sdk::{contract, contractimpl, Env};
+
+    #[contract]
+    pub struct MockWideToken;
+
+    #[contractimpl]
+    impl MockWideToken {
+        pub fn decimals(_env: Env) -> u32 {
+            24
+        }
+    }
+}
+
+// Soroswap router stand-in: swaps along a path at admin-set rates scaled by
+// 1e18 and holds every pair's liquidity itself
+#[contract]
+pub struct MockSoroswapRouter;
+
+#[contractimpl]
+impl MockSoroswapRouter {
+    pub fn set_rate(env: Env, token_in: Address, token_out: Address, rate: u128) {
+        env.storage().persistent().set(&(token_in, token_out), &rate);
+    }
+}
+
+fn router_amounts(env: &Env, amount_in: i128, path: &Vec<Address>) -> Vec<i128> {
+    let mut amounts = vec![env, amount_in];
+    for i in 1..path.len() {
+        let rate: u128 = env.storage()
+            .persistent()
+            .get(&(path.get_unchecked(i - 1), path.get_unchecked(i)))
+            .unwrap();
+        amounts.push_back((amounts.last_unchecked() as u128 * rate / PRICE_SCALE) as i128);
+    }
+    amounts
+}
+
+#[contractimpl]
+impl SoroswapRouterInterface for MockSoroswapRouter {
+    fn swap_exact_tokens_for_tokens(
+        env: Env,
+        amount_in: i128,
+        amount_out_min: i128,
+        path: Vec<Address>,
+        to: Address,
+        deadline: u64,
+    ) -> Vec<i128> {
+        to.require_auth();
+        if env.ledger().timestamp() > deadline {
+            panic!("deadline expired");
+        }
+
+        let amounts = router_amounts(&env, amount_in, &path);
+        if amounts.last_unchecked() < amount_out_min {
+            panic!("insufficient output amount");
+        }
+
+        let router = env.current_contract_address();
+        token::Client::new(&env, &path.first_unchecked()).transfer(&to, &router, &amount_in);
+        token::Client::new(&env, &path.last_unchecked()).transfer(&router, &to, &amounts.last_unchecked());
+        amounts
+    }
+
+    fn router_pair_for(env: Env, _token_a: Address, _token_b: Address) -> Address {
+        env.current_contract_address()
+    }
+
+    fn router_get_amounts_out(env: Env, amount_in: i128, path: Vec<Address>) -> Vec<i128> {
+        router_amounts(&env, amount_in, &path)
+    }
+
+    fn router_get_amounts_in(env: Env, amount_out: i128, path: Vec<Address>) -> Vec<i128> {
+        let mut amounts = vec![&env, amount_out];
+        for i in (1..path.len()).rev() {
+            let rate: u128 = env.storage()
+                .persistent()
+                .get(&(path.get_unchecked(i - 1), path.get_unchecked(i)))
+                .unwrap();
+            let amount = amounts.first_unchecked() as u128 * PRICE_SCALE;
+            amounts.push_front(amount.div_ceil(rate) as i128);
+        }
+        amounts
+    }
+}
+
+// ===================
+// TEST SETUP
+// ===================
+
+const UNIT: i128 = 10_000_000; // 1 token with 7 decimals (all Stellar asset contracts)
+
+struct TestEnv<'a> {
+    env: Env,
+    admin: Address,
+    hub: StellarDeFiHubClient<'a>,
+    pool: MockBlendPoolClient<'a>,
+    oracle: MockOracleClient<'a>,
+    router: MockSoroswapRouterClient<'a>,
+    xlm: Address,
+    blnd: Address,
+    usdc: Address,
+    weth: Address,
+    wbtc: Address,
+}
+
+impl<'a> TestEnv<'a> {
+    fn new() -> Self {
+        let env = Env::default();
+        // The hub authorizes Blend's token pulls from inside its own calls
+        env.mock_all_auths_allowing_non_root_auth();
+        // Entries outlive a few days of ledgers, but not the hub's TTL thresholds
+        env.ledger().with_mut(|li| {
+            li.timestamp = 1_000_000;
+            li.min_persistent_entry_ttl = 5 * DAY_IN_LEDGERS;
+        });
+
+        let admin = Address::generate(&env);
+        let xlm = env.register_stellar_asset_contract_v2(admin.clone()).address();
+        let blnd = env.register_stellar_asset_contract_v2(admin.clone()).address();
+        let usdc = env.register_stellar_asset_contract_v2(admin.clone()).address();
+        let weth = env.register_stellar_asset_contract_v2(admin.clone()).address();
+        let wbtc = env.register_stellar_asset_contract_v2(admin.clone()).address();
+
+        let pool = MockBlendPoolClient::new(&env, &env.register_contract(None, MockBlendPool));
+        let oracle = MockOracleClient::new(&env, &env.register_contract(None, MockOracle));
+        let router = MockSoroswapRouterClient::new(&env, &env.register_contract(None, MockSoroswapRouter));
+        let hub = StellarDeFiHubClient::new(&env, &env.register_contract(None, StellarDeFiHub));
+
+        let asset = |address: &Address, symbol: &str, ltv: u32, threshold: u32, reserve_index: u32, bonus: u32| AssetConfig {
+            address: address.clone(),
+            symbol: String::from_str(&env, symbol),
+            decimals: 7,
+            is_active: true,
+            ltv_ratio: ltv,
+            liquidation_threshold_bps: threshold,
+            reserve_index,
+            liquidation_bonus: bonus,
+            max_price_age: 3600,
+        };
+        let rate = |from: &Address, to: &Address, rate: u128| ExchangeRate {
+            from_asset: from.clone(),
+            to_asset: to.clone(),
+            rate,
+            last_update: 0,
+        };
+
+        let config = HubConfig {
+            blend_pool: pool.address.clone(),
+            backstop: Address::generate(&env),
+            oracle: oracle.address.clone(),
+            blend_token: blnd.clone(),
+            swap_router: router.address.clone(),
+            assets: vec![
+                &env,
+                asset(&xlm, "XLM", 7000, 7500, 0, 500),
+                asset(&blnd, "BLND", 6500, 7000, 1, 800),
+                asset(&usdc, "USDC", 8500, 9000, 2, 300),
+                asset(&weth, "wETH", 7500, 8000, 3, 600),
+                asset(&wbtc, "wBTC", 7500, 8000, 4, 700),
+            ],
+            exchange_rates: vec![
+                &env,
+                rate(&xlm, &usdc, 120_000_000_000_000_000),
+                rate(&usdc, &xlm, 8_333_333_333_333_333_333),
+                rate(&blnd, &usdc, 50_000_000_000_000_000),
+                rate(&usdc, &blnd, 20_000_000_000_000_000_000),
+            ],
+        };
+        hub.initialize(&admin, &config);
+
+        for asset in config.assets.iter() {
+            pool.set_reserve(&asset.address, &asset.reserve_index);
+        }
+
+        oracle.set_price(&xlm, &120_000_000_000_000_000);
+        oracle.set_price(&blnd, &50_000_000_000_000_000);
+        oracle.set_price(&usdc, &PRICE_SCALE);
+        oracle.set_price(&weth, &(2_500 * PRICE_SCALE));
+        oracle.set_price(&wbtc, &(45_000 * PRICE_SCALE));
+
+        // Pool lending liquidity, hub fixed-rate liquidity and router liquidity
+        for token in [&xlm, &blnd, &usdc, &weth, &wbtc] {
+            let sac = token::StellarAssetClient::new(&env, token);
+            sac.mint(&pool.address, &(1_000_000 * UNIT));
+            pool.sync_liquidity(token);
+            sac.mint(&hub.address, &(1_000_000 * UNIT));
+            sac.mint(&router.address, &(1_000_000 * UNIT));
+        }
+
+        TestEnv { env, admin, hub, pool, oracle, router, xlm, blnd, usdc, weth, wbtc }
+    }
+
+    fn mint(&self, token: &Address, to: &Address, amount: i128) {
+        token::StellarAssetClient::new(&self.env, token).mint(to, &amount);
+    }
+
+    fn balance(&self, token: &Address, id: &Address) -> i128 {
+        token::Client::new(&self.env, token).balance(id)
+    }
+
+    // User with 1000 USDC of collateral and a 5000 XLM ($600) loan
+    fn borrower(&self) -> Address {
+        let user = Address::generate(&self.env);
+        self.mint(&self.usdc, &user, 1_000 * UNIT);
+        self.hub.supply_to_blend(&user, &self.usdc, &(1_000 * UNIT), &true);
+        self.hub.borrow_from_blend(&user, &self.xlm, &(5_000 * UNIT));
+        user
+    }
+}
+
+// ===================
+// TESTS
+// ===================
+
+#[test]
+fn test_initialize() {
+    let t = TestEnv::new();
+    let position = t.hub.get_user_position(&t.admin);
+    assert_eq!(position.health_factor, HEALTH_FACTOR_SCALE * 1000);
+    assert_eq!(t.hub.get_asset_price(&t.weth), 2_500 * PRICE_SCALE);
+    assert_eq!(t.hub.get_asset_price(&t.wbtc), 45_000 * PRICE_SCALE);
+
+    // A second initialize can't replace the admin or the external contracts
+    let attacker = Address::generate(&t.env);
+    let config = HubConfig {
+        blend_pool: attacker.clone(),
+        backstop: attacker.clone(),
+        oracle: attacker.clone(),
+        blend_token: attacker.clone(),
+        swap_router: attacker.clone(),
+        assets: Vec::new(&t.env),
+        exchange_rates: Vec::new(&t.env),
+    };
+    let result = t.hub.try_initialize(&attacker, &config);
+    assert_eq!(result, Err(Ok(HubError::AlreadyInitialized)));
+    let result = t.hub.try_set_circuit_breaker(&attacker, &CircuitBreakerConfig { max_move_bps: 0, window: 0, grace_period: 0 });
+    assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+}
+
+#[test]
+fn test_oracle_prices() {
+    let t = TestEnv::new();
+    let user = t.borrower();
+
+    // 14-decimal SEP-40 prices are normalized to 1e18
+    assert_eq!(t.hub.get_asset_price(&t.xlm), 120_000_000_000_000_000);
+
+    // Missing prices are errors rather than hard-coded defaults
+    t.oracle.clear_price(&t.weth);
+    assert_eq!(t.hub.try_get_asset_price(&t.weth), Err(Ok(HubError::PriceOracleError)));
+
+    // Prices older than max_price_age are stale
+    t.env.ledger().with_mut(|li| li.timestamp += 3_601);
+    assert_eq!(t.hub.try_get_asset_price(&t.xlm), Err(Ok(HubError::PriceOracleError)));
+    let result = t.hub.try_borrow_from_blend(&user, &t.xlm, &UNIT);
+    assert_eq!(result, Err(Ok(HubError::PriceOracleError)));
+
+    t.oracle.set_price(&t.xlm, &120_000_000_000_000_000);
+    t.oracle.set_price(&t.usdc, &PRICE_SCALE);
+    t.hub.borrow_from_blend(&user, &t.xlm, &UNIT);
+
+    // So are prices stamped after the current ledger
+    t.env.ledger().with_mut(|li| li.timestamp -= 1);
+    assert_eq!(t.hub.try_get_asset_price(&t.xlm), Err(Ok(HubError::PriceOracleError)));
+}
+
+#[test]
+fn test_price_source_median() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+    let second = MockOracleClient::new(&t.env, &t.env.register_contract(None, MockOracle));
+    let third = MockOracleClient::new(&t.env, &t.env.register_contract(None, MockOracle));
+    let sources = PriceSourceConfig {
+        oracles: vec![&t.env, t.oracle.address.clone(), second.address.clone(), third.address.clone()],
+        quorum: 2,
+        max_deviation_bps: 200,
+    };
+
+    // Without registered sources the hub oracle is used alone
+    let used = t.hub.get_price_sources(&t.xlm);
+    assert_eq!(used.len(), 1);
+    assert_eq!(used.get(0).unwrap().oracle, t.oracle.address);
+
+    let result = t.hub.try_set_price_sources(&user, &t.xlm, &sources);
+    assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+    for invalid in [
+        PriceSourceConfig { quorum: 0, ..sources.clone() },
+        PriceSourceConfig { quorum: 4, ..sources.clone() },
+        PriceSourceConfig { max_deviation_bps: 10_001, ..sources.clone() },
+        PriceSourceConfig { oracles: vec![&t.env, second.address.clone(), second.address.clone()], ..sources.clone() },
+    ] {
+        let result = t.hub.try_set_price_sources(&t.admin, &t.xlm, &invalid);
+        assert_eq!(result, Err(Ok(HubError::InvalidAssetConfig)));
+    }
+    t.hub.set_price_sources(&t.admin, &t.xlm, &sources);
+
+    second.set_price(&t.xlm, &121_000_000_000_000_000);
+    third.set_price(&t.xlm, &119_000_000_000_000_000);
+    assert_eq!(t.hub.get_asset_price(&t.xlm), 120_000_000_000_000_000);
+
+    // Stale answers are left out and the two fresh ones are averaged
+    t.env.ledger().with_mut(|li| li.timestamp += 3_601);
+    t.oracle.set_price(&t.xlm, &120_000_000_000_000_000);
+    second.set_price(&t.xlm, &121_000_000_000_000_000);
+    assert_eq!(t.hub.get_asset_price(&t.xlm), 120_500_000_000_000_000);
+    let used = t.hub.get_price_sources(&t.xlm);
+    assert_eq!(used.get(2).unwrap().price, 119_000_000_000_000_000);
+    assert!(used.get(0).unwrap().used && used.get(1).unwrap().used && !used.get(2).unwrap().used);
+
+    // A missing answer drops the fresh count below quorum
+    second.clear_price(&t.xlm);
+    assert_eq!(t.hub.try_get_asset_price(&t.xlm), Err(Ok(HubError::PriceOracleError)));
+
+    // Answers more than 2% from the median are rejected
+    second.set_price(&t.xlm, &130_000_000_000_000_000);
+    third.set_price(&t.xlm, &121_000_000_000_000_000);
+    assert_eq!(t.hub.try_get_asset_price(&t.xlm), Err(Ok(HubError::PriceOracleError)));
+    second.set_price(&t.xlm, &122_000_000_000_000_000);
+    assert_eq!(t.hub.get_asset_price(&t.xlm), 121_000_000_000_000_000);
+}
+
+#[test]
+fn test_fallback_prices() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+    let weth_fallback = 2_400 * PRICE_SCALE;
+
+    let result = t.hub.try_set_fallback_price(&user, &t.weth, &weth_fallback, &600);
+    assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+    let result = t.hub.try_set_fallback_price(&t.admin, &user, &weth_fallback, &600);
+    assert_eq!(result, Err(Ok(HubError::InvalidAsset)));
+    let result = t.hub.try_set_fallback_price(&t.admin, &t.weth, &0, &600);
+    assert_eq!(result, Err(Ok(HubError::InvalidAmount)));
+    t.hub.set_fallback_price(&t.admin, &t.weth, &weth_fallback, &600);
+
+    // Oracle prices take precedence while they are available
+    assert_eq!(t.hub.get_asset_price(&t.weth), 2_500 * PRICE_SCALE);
+
+    // Unknown assets are never priced
+    assert_eq!(t.hub.try_get_asset_price(&user), Err(Ok(HubError::InvalidAsset)));
+
+    t.oracle.clear_price(&t.weth);
+    assert_eq!(t.hub.get_asset_price(&t.weth), weth_fallback);
+    let event = t.env.events().all().last().unwrap();
+    assert_eq!(
+        vec![&t.env, event],
+        vec![
+            &t.env,
+            (
+                t.hub.address.clone(),
+                (symbol_short!("fallback"), t.weth.clone()).into_val(&t.env),
+                (weth_fallback, t.env.ledger().timestamp()).into_val(&t.env),
+            ),
+        ]
+    );
+
+    // Fallbacks expire after their validity window
+    t.env.ledger().with_mut(|li| li.timestamp += 601);
+    assert_eq!(t.hub.try_get_asset_price(&t.weth), Err(Ok(HubError::PriceOracleError)));
+
+    // Without prices, debt can still be repaid and debt-free supply withdrawn
+    let borrower = t.borrower();
+    let lender = Address::generate(&t.env);
+    t.mint(&t.weth, &lender, UNIT);
+    t.hub.supply_to_blend(&lender, &t.weth, &UNIT, &true);
+    t.oracle.clear_price(&t.xlm);
+    t.oracle.clear_price(&t.usdc);
+    let result = t.hub.try_borrow_from_blend(&borrower, &t.xlm, &UNIT);
+    assert_eq!(result, Err(Ok(HubError::PriceOracleError)));
+    t.hub.repay_to_blend(&borrower, &borrower, &t.xlm, &(1_000 * UNIT));
+    t.hub.withdraw_from_blend(&lender, &t.weth, &UNIT);
+    assert_eq!(t.balance(&t.weth, &lender), UNIT);
+}
+
+#[test]
+fn test_entrypoints_require_initialize() {
+    let env = Env::default();
+    env.mock_all_auths();
+    let hub = StellarDeFiHubClient::new(&env, &env.register_contract(None, StellarDeFiHub));
+    let user = Address::generate(&env);
+
+    let result = hub.try_supply_to_blend(&user, &Address::generate(&env), &UNIT, &true);
+    assert_eq!(result, Err(Ok(HubError::NotInitialized)));
+}
+
+#[test]
+fn test_supply_and_withdraw() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+    t.mint(&t.xlm, &user, 1_000 * UNIT);
+
+    t.hub.supply_to_blend(&user, &t.xlm, &(1_000 * UNIT), &true);
+    assert_eq!(t.balance(&t.xlm, &user), 0);
+    assert_eq!(t.balance(&t.xlm, &t.pool.address), 1_001_000 * UNIT);
+    let position = t.hub.get_user_position(&user);
+    assert_eq!(position.collateral_assets.get(t.xlm.clone()), Some(1_000 * UNIT));
+
+    // The hub's Blend collateral mirrors the user's supply
+    let blend_positions = t.pool.get_positions(&t.hub.address);
+    assert_eq!(blend_positions.collateral.get(0), Some(1_000 * UNIT));
+
+    t.hub.withdraw_from_blend(&user, &t.xlm, &(400 * UNIT));
+    assert_eq!(t.balance(&t.xlm, &user), 400 * UNIT);
+    let position = t.hub.get_user_position(&user);
+    assert_eq!(position.collateral_assets.get(t.xlm.clone()), Some(600 * UNIT));
+
+    let result = t.hub.try_withdraw_from_blend(&user, &t.xlm, &(601 * UNIT));
+    assert_eq!(result, Err(Ok(HubError::InsufficientBalance)));
+}
+
+#[test]
+fn test_lend_only_supply_and_collateral_toggles() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+    t.mint(&t.usdc, &user, 1_500 * UNIT);
+
+    // Lend-only supply earns in Blend but can't back a loan
+    t.hub.supply_to_blend(&user, &t.usdc, &(1_000 * UNIT), &false);
+    let position = t.hub.get_user_position(&user);
+    assert_eq!(position.supplied_assets.get(t.usdc.clone()), Some(1_000 * UNIT));
+    assert_eq!(position.collateral_assets.get(t.usdc.clone()), None);
+    assert_eq!(t.pool.get_positions(&t.hub.address).supply.get(2), Some(1_000 * UNIT));
+    let result = t.hub.try_borrow_from_blend(&user, &t.xlm, &UNIT);
+    assert_eq!(result, Err(Ok(HubError::UnhealthyPosition)));
+
+    t.hub.enable_collateral(&user, &t.usdc);
+    let position = t.hub.get_user_position(&user);
+    assert_eq!(position.supplied_assets.get(t.usdc.clone()), None);
+    assert_eq!(position.collateral_assets.get(t.usdc.clone()), Some(1_000 * UNIT));
+    let blend_positions = t.pool.get_positions(&t.hub.address);
+    assert_eq!(blend_positions.supply.get(2), Some(0));
+    assert_eq!(blend_positions.collateral.get(2), Some(1_000 * UNIT));
+
+    // Collateral backing a loan can't be released
+    t.hub.borrow_from_blend(&user, &t.xlm, &(5_000 * UNIT));
+    let result = t.hub.try_disable_collateral(&user, &t.usdc);
+    assert_eq!(result, Err(Ok(HubError::UnhealthyPosition)));
+
+    t.hub.repay_to_blend(&user, &user, &t.xlm, &(5_000 * UNIT));
+    t.hub.disable_collateral(&user, &t.usdc);
+    let position = t.hub.get_user_position(&user);
+    assert_eq!(position.supplied_assets.get(t.usdc.clone()), Some(1_000 * UNIT));
+    assert_eq!(position.collateral_assets.get(t.usdc.clone()), None);
+
+    // Withdrawals draw on lend-only balance before collateral
+    t.hub.supply_to_blend(&user, &t.usdc, &(500 * UNIT), &true);
+    t.hub.withdraw_from_blend(&user, &t.usdc, &(1_200 * UNIT));
+    let position = t.hub.get_user_position(&user);
+    assert_eq!(position.supplied_assets.get(t.usdc.clone()), None);
+    assert_eq!(position.collateral_assets.get(t.usdc.clone()), Some(300 * UNIT));
+    assert_eq!(t.hub.reconcile(&t.usdc).drift_bps, 0);
+}
+
+#[test]
+fn test_blend_v2_rate_scale() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+    t.mint(&t.usdc, &user, 1_050 * UNIT);
+
+    // Rates are reported with 12 decimals: 1 bToken is 1.05 USDC
+    t.pool.set_rates(&t.usdc, &1_050_000_000_000, &RATE_SCALAR);
+    t.hub.supply_to_blend(&user, &t.usdc, &(1_050 * UNIT), &true);
+    let index = t.hub.get_supported_assets().get(2).unwrap().reserve_index;
+    assert_eq!(t.pool.get_positions(&t.hub.address).collateral.get(index), Some(1_000 * UNIT));
+    assert_eq!(t.hub.get_user_position(&user).collateral_assets.get(t.usdc.clone()), Some(1_050 * UNIT));
+
+    // 2,000 XLM of debt at a d_rate of 1.1 is 1,818.1818182 dTokens, rounded up
+    t.pool.set_rates(&t.xlm, &RATE_SCALAR, &1_100_000_000_000);
+    t.hub.borrow_from_blend(&user, &t.xlm, &(2_000 * UNIT));
+    let index = t.hub.get_supported_assets().get(0).unwrap().reserve_index;
+    assert_eq!(t.pool.get_positions(&t.hub.address).liabilities.get(index), Some(18_181_818_182));
+    assert_eq!(t.hub.get_user_position(&user).borrowed_assets.get(t.xlm.clone()), Some(2_000 * UNIT + 1));
+}
+
+#[test]
+fn test_positions_accrue_blend_interest() {
+    let t = TestEnv::new();
+    let user = t.borrower();
+
+    // 10% supply yield on USDC and 20% interest on XLM debt
+    t.pool.set_rates(&t.usdc, &1_100_000_000_000, &RATE_SCALAR);
+    t.pool.set_rates(&t.xlm, &RATE_SCALAR, &1_200_000_000_000);
+
+    let position = t.hub.get_user_position(&user);
+    assert_eq!(position.collateral_assets.get(t.usdc.clone()), Some(1_100 * UNIT));
+    assert_eq!(position.borrowed_assets.get(t.xlm.clone()), Some(6_000 * UNIT));
+    // $990 of threshold-weighted collateral against $720 of debt
+    assert_eq!(position.health_factor, 1_375_000_000_000_000_000);
+
+    // Repaying the grown debt clears the dTokens
+    t.mint(&t.xlm, &user, 2_000 * UNIT);
+    assert_eq!(t.hub.repay_to_blend(&user, &user, &t.xlm, &(7_000 * UNIT)), 6_000 * UNIT);
+    assert_eq!(t.balance(&t.xlm, &user), 1_000 * UNIT);
+    let position = t.hub.get_user_position(&user);
+    assert_eq!(position.borrowed_assets.get(t.xlm.clone()), None);
+
+    // The yield can be withdrawn
+    t.hub.withdraw_from_blend(&user, &t.usdc, &(1_100 * UNIT));
+    assert_eq!(t.balance(&t.usdc, &user), 1_100 * UNIT);
+    let position = t.hub.get_user_position(&user);
+    assert_eq!(position.collateral_assets.get(t.usdc.clone()), None);
+}
+
+#[test]
+fn test_submit_rejects_unchanged_blend_positions() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+    t.mint(&t.xlm, &user, 1_000 * UNIT);
+
+    t.pool.set_broken(&true);
+    let result = t.hub.try_supply_to_blend(&user, &t.xlm, &(1_000 * UNIT), &true);
+    assert_eq!(result, Err(Ok(HubError::BlendError)));
+    assert_eq!(t.balance(&t.xlm, &user), 1_000 * UNIT);
+}
+
+#[test]
+fn test_supply_rejects_invalid_input() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+
+    let result = t.hub.try_supply_to_blend(&user, &t.xlm, &0, &true);
+    assert_eq!(result, Err(Ok(HubError::InvalidAmount)));
+
+    let result = t.hub.try_supply_to_blend(&user, &t.xlm, &-UNIT, &true);
+    assert_eq!(result, Err(Ok(HubError::InvalidAmount)));
+
+    let result = t.hub.try_supply_to_blend(&user, &Address::generate(&t.env), &UNIT, &true);
+    assert_eq!(result, Err(Ok(HubError::InvalidAsset)));
+
+    // Token transfer failures surface as a missing balance
+    let result = t.hub.try_supply_to_blend(&user, &t.xlm, &UNIT, &true);
+    assert_eq!(result, Err(Ok(HubError::InsufficientBalance)));
+
+    t.pool.set_status(&4);
+    let result = t.hub.try_supply_to_blend(&user, &t.xlm, &UNIT, &true);
+    assert_eq!(result, Err(Ok(HubError::PoolFrozen)));
+}
+
+#[test]
+fn test_borrow() {
+    let t = TestEnv::new();
+    let user = t.borrower();
+
+    assert_eq!(t.balance(&t.xlm, &user), 5_000 * UNIT);
+    let position = t.hub.get_user_position(&user);
+    assert_eq!(position.borrowed_assets.get(t.xlm.clone()), Some(5_000 * UNIT));
+    // $900 of threshold-weighted collateral against $600 of debt
+    assert_eq!(position.health_factor, 1_500_000_000_000_000_000);
+    assert_eq!(t.hub.get_health_status(&user), HealthStatus::Healthy);
+}
+
+#[test]
+fn test_blend_factors() {
+    let t = TestEnv::new();
+    let user = t.borrower();
+
+    let result = t.hub.try_set_blend_factors(&user, &true);
+    assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+
+    // $900 of collateral at 0.9 against $600 / 0.9 of liabilities
+    t.hub.set_blend_factors(&t.admin, &true);
+    assert_eq!(t.hub.get_user_position(&user).health_factor, 1_350_000_000_000_000_000);
+
+    // The borrow limit is $850 against liabilities inflated by l_factor
+    let result = t.hub.try_borrow_from_blend(&user, &t.xlm, &(1_400 * UNIT));
+    assert_eq!(result, Err(Ok(HubError::UnhealthyPosition)));
+    t.hub.borrow_from_blend(&user, &t.xlm, &(1_300 * UNIT));
+
+    // A lower Blend c_factor wins over the local threshold: $800 against $840
+    t.pool.set_factors(&t.usdc, &8_000_000, &9_000_000);
+    assert_eq!(t.hub.get_user_position(&user).health_factor, 952_380_952_380_952_380);
+
+    // A reserve index that no longer matches Blend is rejected
+    t.pool.set_reserve(&t.usdc, &7);
+    let result = t.hub.try_get_user_position(&user);
+    assert_eq!(result, Err(Ok(HubError::InvalidAssetConfig)));
+
+    t.hub.set_blend_factors(&t.admin, &false);
+    t.pool.set_reserve(&t.usdc, &2);
+    assert_eq!(t.hub.get_user_position(&user).health_factor, 1_190_476_190_476_190_476);
+}
+
+#[test]
+fn test_borrow_rejects_unhealthy_position() {
+    let t = TestEnv::new();
+    let user = t.borrower();
+
+    // Another 2100 XLM ($252) of debt would exceed the $850 USDC borrow limit
+    let result = t.hub.try_borrow_from_blend(&user, &t.xlm, &(2_100 * UNIT));
+    assert_eq!(result, Err(Ok(HubError::UnhealthyPosition)));
+
+    t.pool.set_status(&2);
+    let result = t.hub.try_borrow_from_blend(&user, &t.xlm, &UNIT);
+    assert_eq!(result, Err(Ok(HubError::BorrowingDisabled)));
+}
+
+#[test]
+fn test_withdraw_rejects_unhealthy_position() {
+    let t = TestEnv::new();
+    let user = t.borrower();
+
+    let result = t.hub.try_withdraw_from_blend(&user, &t.usdc, &(400 * UNIT));
+    assert_eq!(result, Err(Ok(HubError::UnhealthyPosition)));
+}
+
+#[test]
+fn test_repay_on_behalf() {
+    let t = TestEnv::new();
+    let user = t.borrower();
+    let treasury = Address::generate(&t.env);
+    t.mint(&t.xlm, &treasury, 10_000 * UNIT);
+
+    let repaid = t.hub.repay_to_blend(&treasury, &user, &t.xlm, &(2_000 * UNIT));
+    assert_eq!(repaid, 2_000 * UNIT);
+    let position = t.hub.get_user_position(&user);
+    assert_eq!(position.borrowed_assets.get(t.xlm.clone()), Some(3_000 * UNIT));
+
+    // Overpayment is capped at the recorded debt and refunded
+    let repaid = t.hub.repay_to_blend(&treasury, &user, &t.xlm, &(5_000 * UNIT));
+    assert_eq!(repaid, 3_000 * UNIT);
+    assert_eq!(t.balance(&t.xlm, &treasury), 5_000 * UNIT);
+    let position = t.hub.get_user_position(&user);
+    assert_eq!(position.borrowed_assets.get(t.xlm.clone()), None);
+
+    let result = t.hub.try_repay_to_blend(&treasury, &user, &t.xlm, &UNIT);
+    assert_eq!(result, Err(Ok(HubError::InsufficientDebt)));
+}
+
+#[test]
+fn test_swap_fixed_rate() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+    t.mint(&t.xlm, &user, 1_000 * UNIT);
+
+    let deadline = t.env.ledger().timestamp() + 60;
+    let amount_out = t.hub.swap_tokens(&user, &t.xlm, &t.usdc, &(1_000 * UNIT), &0, &deadline);
+
+    // 0.5% fee, then 995 XLM at 0.12 USDC
+    assert_eq!(amount_out, 1_194_000_000);
+    assert_eq!(t.balance(&t.usdc, &user), 1_194_000_000);
+    assert_eq!(t.balance(&t.xlm, &user), 0);
+}
+
+#[test]
+fn test_swap_oracle_fallback() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+    t.mint(&t.weth, &user, UNIT);
+
+    // No wETH -> USDC rate is configured, so oracle prices are used
+    let deadline = t.env.ledger().timestamp() + 60;
+    let amount_out = t.hub.swap_tokens(&user, &t.weth, &t.usdc, &UNIT, &0, &deadline);
+    assert_eq!(amount_out, 24_875_000_000);
+}
+
+#[test]
+fn test_quote_swap_matches_execution() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+    let now = t.env.ledger().timestamp();
+    let deadline = now + 600;
+
+    let result = t.hub.try_quote_swap(&t.xlm, &t.usdc, &0);
+    assert_eq!(result, Err(Ok(HubError::InvalidAmount)));
+    let result = t.hub.try_quote_swap(&t.xlm, &t.xlm, &UNIT);
+    assert_eq!(result, Err(Ok(HubError::InvalidAsset)));
+
+    // Fixed rate, set at initialization
+    t.env.ledger().with_mut(|li| li.timestamp = now + 100);
+    let quote = t.hub.quote_swap(&t.xlm, &t.usdc, &(1_000 * UNIT));
+    assert_eq!(
+        quote,
+        SwapQuote {
+            amount_out: 1_194_000_000,
+            fee: 5 * UNIT,
+            rate: 120_000_000_000_000_000,
+            source: SwapSource::FixedRate,
+            last_update: now,
+        }
+    );
+    t.mint(&t.xlm, &user, 1_000 * UNIT);
+    let amount_out = t.hub.swap_tokens(&user, &t.xlm, &t.usdc, &(1_000 * UNIT), &0, &deadline);
+    assert_eq!(amount_out, quote.amount_out);
+
+    // Oracle cross price
+    let quote = t.hub.quote_swap(&t.weth, &t.usdc, &UNIT);
+    assert_eq!(quote.source, SwapSource::Oracle);
+    assert_eq!(quote.rate, 2_500 * PRICE_SCALE);
+    assert_eq!(quote.last_update, now + 100);
+    t.mint(&t.weth, &user, UNIT);
+    let amount_out = t.hub.swap_tokens(&user, &t.weth, &t.usdc, &UNIT, &0, &deadline);
+    assert_eq!(amount_out, quote.amount_out);
+
+    // Router path
+    let route = SwapRoute::Router(vec![&t.env, t.xlm.clone(), t.usdc.clone()]);
+    t.hub.set_swap_route(&t.admin, &t.xlm, &t.usdc, &route);
+    t.router.set_rate(&t.xlm, &t.usdc, &119_000_000_000_000_000);
+    let quote = t.hub.quote_swap(&t.xlm, &t.usdc, &(1_000 * UNIT));
+    assert_eq!(quote.source, SwapSource::Router);
+    assert_eq!(quote.rate, 119_000_000_000_000_000);
+    t.mint(&t.xlm, &user, 1_000 * UNIT);
+    let amount_out = t.hub.swap_tokens(&user, &t.xlm, &t.usdc, &(1_000 * UNIT), &0, &deadline);
+    assert_eq!(amount_out, quote.amount_out);
+}
+
+#[test]
+fn test_swap_tokens_for_exact() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+    t.mint(&t.xlm, &user, 2_000 * UNIT);
+    let deadline = t.env.ledger().timestamp() + 60;
+
+    let result = t.hub.try_swap_tokens_for_exact(&user, &t.xlm, &t.usdc, &0, &(1_000 * UNIT), &deadline);
+    assert_eq!(result, Err(Ok(HubError::InvalidAmount)));
+
+    // 100 USDC at 0.12 needs 833.3333334 XLM, plus the 0.5% fee
+    let amount_in = 8_375_209_380;
+    let result = t.hub.try_swap_tokens_for_exact(&user, &t.xlm, &t.usdc, &(100 * UNIT), &(amount_in - 1), &deadline);
+    assert_eq!(result, Err(Ok(HubError::SlippageExceeded)));
+    let charged = t.hub.swap_tokens_for_exact(&user, &t.xlm, &t.usdc, &(100 * UNIT), &(1_000 * UNIT), &deadline);
+    assert_eq!(charged, amount_in);
+    assert_eq!(t.balance(&t.usdc, &user), 100 * UNIT);
+    assert_eq!(t.balance(&t.xlm, &user), 2_000 * UNIT - amount_in);
+
+    // It is the smallest input swap_tokens would accept for that output
+    assert!(t.hub.quote_swap(&t.xlm, &t.usdc, &amount_in).amount_out >= 100 * UNIT);
+    assert!(t.hub.quote_swap(&t.xlm, &t.usdc, &(amount_in - 1)).amount_out < 100 * UNIT);
+
+    // Oracle pricing: 1 wETH is 2,500 USDC
+    t.mint(&t.usdc, &user, 3_000 * UNIT);
+    let charged = t.hub.swap_tokens_for_exact(&user, &t.usdc, &t.weth, &UNIT, &(3_000 * UNIT), &deadline);
+    assert_eq!(charged, 25_125_628_140);
+    assert_eq!(t.balance(&t.weth, &user), UNIT);
+
+    // Router routes are priced by the router
+    let route = SwapRoute::Router(vec![&t.env, t.xlm.clone(), t.usdc.clone()]);
+    t.hub.set_swap_route(&t.admin, &t.xlm, &t.usdc, &route);
+    t.router.set_rate(&t.xlm, &t.usdc, &119_000_000_000_000_000);
+    let usdc = t.balance(&t.usdc, &user);
+    let charged = t.hub.swap_tokens_for_exact(&user, &t.xlm, &t.usdc, &(50 * UNIT), &(1_000 * UNIT), &deadline);
+    assert!(t.hub.quote_swap(&t.xlm, &t.usdc, &(charged - 1)).amount_out < 50 * UNIT);
+    assert!(t.balance(&t.usdc, &user) >= usdc + 50 * UNIT);
+}
+
+#[test]
+fn test_swap_errors() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+    t.mint(&t.xlm, &user, 1_000 * UNIT);
+    let now = t.env.ledger().timestamp();
+
+    let result = t.hub.try_swap_tokens(&user, &t.xlm, &t.usdc, &(1_000 * UNIT), &0, &(now - 1));
+    assert_eq!(result, Err(Ok(HubError::DeadlineExpired)));
+
+    let result = t.hub.try_swap_tokens(&user, &t.xlm, &t.usdc, &(1_000 * UNIT), &(120 * UNIT), &now);
+    assert_eq!(result, Err(Ok(HubError::SlippageExceeded)));
+}
+
+#[test]
+fn test_swap_router() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+    t.mint(&t.xlm, &user, 1_000 * UNIT);
+    let deadline = t.env.ledger().timestamp() + 60;
+    let direct = SwapRoute::Router(vec![&t.env, t.xlm.clone(), t.usdc.clone()]);
+
+    let result = t.hub.try_set_swap_route(&user, &t.xlm, &t.usdc, &direct);
+    assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+    for invalid in [
+        SwapRoute::Router(vec![&t.env, t.xlm.clone()]),
+        SwapRoute::Router(vec![&t.env, t.usdc.clone(), t.xlm.clone()]),
+    ] {
+        let result = t.hub.try_set_swap_route(&t.admin, &t.xlm, &t.usdc, &invalid);
+        assert_eq!(result, Err(Ok(HubError::InvalidAsset)));
+    }
+    t.hub.set_swap_route(&t.admin, &t.xlm, &t.usdc, &direct);
+    t.router.set_rate(&t.xlm, &t.usdc, &119_000_000_000_000_000);
+
+    // The fee stays with the hub and the rest is swapped by the router
+    let hub_xlm = t.balance(&t.xlm, &t.hub.address);
+    let amount_out = t.hub.swap_tokens(&user, &t.xlm, &t.usdc, &(1_000 * UNIT), &0, &deadline);
+    assert_eq!(amount_out, 1_184_050_000);
+    assert_eq!(t.balance(&t.usdc, &user), amount_out);
+    assert_eq!(t.balance(&t.xlm, &t.hub.address), hub_xlm + 5 * UNIT);
+    assert_eq!(t.balance(&t.xlm, &t.router.address), 1_000_995 * UNIT);
+
+    // Router reverts, including its own slippage check, fail the swap
+    t.mint(&t.xlm, &user, 1_000 * UNIT);
+    let result = t.hub.try_swap_tokens(&user, &t.xlm, &t.usdc, &(1_000 * UNIT), &(120 * UNIT), &deadline);
+    assert_eq!(result, Err(Ok(HubError::SwapFailed)));
+
+    // Multi-hop paths go through intermediate tokens
+    let path = SwapRoute::Router(vec![&t.env, t.weth.clone(), t.xlm.clone(), t.usdc.clone()]);
+    t.hub.set_swap_route(&t.admin, &t.weth, &t.usdc, &path);
+    t.router.set_rate(&t.weth, &t.xlm, &(20_000 * PRICE_SCALE));
+    t.mint(&t.weth, &user, UNIT);
+    let amount_out = t.hub.swap_tokens(&user, &t.weth, &t.usdc, &UNIT, &0, &deadline);
+    assert_eq!(amount_out, 23_681_000_000);
+
+    // Pairs can be switched back to fixed rates
+    t.hub.set_swap_route(&t.admin, &t.xlm, &t.usdc, &SwapRoute::FixedRate);
+    let amount_out = t.hub.swap_tokens(&user, &t.xlm, &t.usdc, &(1_000 * UNIT), &0, &deadline);
+    assert_eq!(amount_out, 1_194_000_000);
+}
+
+#[test]
+fn test_swap_tokens_path() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+    t.mint(&t.xlm, &user, 2_000 * UNIT);
+    let deadline = t.env.ledger().timestamp() + 60;
+    let path = vec![&t.env, t.xlm.clone(), t.usdc.clone(), t.blnd.clone()];
+
+    for invalid in [
+        vec![&t.env, t.xlm.clone()],
+        vec![&t.env, t.xlm.clone(), t.xlm.clone(), t.usdc.clone()],
+        vec![&t.env, t.xlm.clone(), user.clone(), t.usdc.clone()],
+    ] {
+        let result = t.hub.try_swap_tokens_path(&user, &invalid, &(1_000 * UNIT), &0, &deadline);
+        assert_eq!(result, Err(Ok(HubError::InvalidAsset)));
+    }
+
+    // 0.5% fee once, 995 XLM -> 119.4 USDC -> 2388 BLND; the minimum only binds the last leg
+    let result = t.hub.try_swap_tokens_path(&user, &path, &(1_000 * UNIT), &(2_388 * UNIT + 1), &deadline);
+    assert_eq!(result, Err(Ok(HubError::SlippageExceeded)));
+    let amount_out = t.hub.swap_tokens_path(&user, &path, &(1_000 * UNIT), &(2_388 * UNIT), &deadline);
+    assert_eq!(amount_out, 2_388 * UNIT);
+    assert_eq!(t.balance(&t.blnd, &user), 2_388 * UNIT);
+
+    let mut hub_events = Vec::new(&t.env);
+    for event in t.env.events().all().iter() {
+        if event.0 == t.hub.address {
+            hub_events.push_back(event);
+        }
+    }
+    let hop = |token_in: &Address, token_out: &Address, amount_in: i128, amount_out: i128| {
+        (
+            t.hub.address.clone(),
+            (symbol_short!("swap_hop"), user.clone()).into_val(&t.env),
+            (token_in.clone(), token_out.clone(), amount_in, amount_out).into_val(&t.env),
+        )
+    };
+    let len = hub_events.len();
+    assert_eq!(
+        hub_events.slice(len - 3..len - 1),
+        vec![
+            &t.env,
+            hop(&t.xlm, &t.usdc, 995 * UNIT, 1_194_000_000),
+            hop(&t.usdc, &t.blnd, 1_194_000_000, 2_388 * UNIT),
+        ]
+    );
+}
+
+#[test]
+fn test_stake_and_unstake_blend() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+    t.mint(&t.blnd, &user, 100 * UNIT);
+
+    t.hub.stake_blend(&user, &(100 * UNIT));
+    assert_eq!(t.balance(&t.blnd, &user), 0);
+    assert_eq!(t.hub.get_user_position(&user).staked_blend, 100 * UNIT);
+
+    // 1% per day
+    t.env.ledger().with_mut(|li| li.timestamp += SECONDS_PER_DAY);
+    let rewards = t.hub.unstake_blend(&user, &(100 * UNIT));
+    assert_eq!(rewards, UNIT);
+    assert_eq!(t.balance(&t.blnd, &user), 101 * UNIT);
+    assert_eq!(t.hub.get_user_position(&user).staked_blend, 0);
+
+    // Rewards are only paid from BLND beyond the stake; the rest stays owed
+    t.hub.stake_blend(&user, &(100 * UNIT));
+    t.env.ledger().with_mut(|li| li.timestamp += SECONDS_PER_DAY);
+    let hub_blnd = t.balance(&t.blnd, &t.hub.address);
+    token::Client::new(&t.env, &t.blnd).burn(&t.hub.address, &(hub_blnd - 100 * UNIT - UNIT / 2));
+    assert_eq!(t.hub.unstake_blend(&user, &0), UNIT / 2);
+    assert_eq!(t.hub.get_user_position(&user).rewards_earned, UNIT / 2);
+    assert_eq!(t.hub.unstake_blend(&user, &(100 * UNIT)), 0);
+    assert_eq!(t.balance(&t.blnd, &user), 101 * UNIT + UNIT / 2);
+    t.mint(&t.blnd, &t.hub.address, UNIT);
+    assert_eq!(t.hub.unstake_blend(&user, &0), UNIT / 2);
+    assert_eq!(t.balance(&t.blnd, &user), 102 * UNIT);
+
+    let result = t.hub.try_unstake_blend(&user, &UNIT);
+    assert_eq!(result, Err(Ok(HubError::InsufficientBalance)));
+    let result = t.hub.try_unstake_blend(&user, &-UNIT);
+    assert_eq!(result, Err(Ok(HubError::InvalidAmount)));
+}
+
+#[test]
+fn test_protocol_fees() {
+    let t = TestEnv::new();
+    let trader = Address::generate(&t.env);
+    let staker_a = Address::generate(&t.env);
+    let staker_b = Address::generate(&t.env);
+    let treasury = Address::generate(&t.env);
+    let deadline = t.env.ledger().timestamp() + 60;
+    t.mint(&t.xlm, &trader, 2_000 * UNIT);
+    t.mint(&t.usdc, &trader, 1_000 * UNIT);
+
+    // With nothing staked the stakers' share goes to the treasury
+    t.hub.swap_tokens(&trader, &t.xlm, &t.usdc, &(1_000 * UNIT), &0, &deadline);
+    let fees = t.hub.get_accrued_fees();
+    assert_eq!(fees.len(), 1);
+    assert_eq!(fees.get(t.xlm.clone()), Some(AccruedFees { stakers: 0, treasury: 5 * UNIT, backstop: 0 }));
+
+    let split = FeeSplit {
+        stakers_bps: 5000,
+        treasury_bps: 3000,
+        backstop_bps: 2000,
+    };
+    let result = t.hub.try_set_fee_split(&trader, &split);
+    assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+    let result = t.hub.try_set_fee_split(&t.admin, &FeeSplit { backstop_bps: 2001, ..split.clone() });
+    assert_eq!(result, Err(Ok(HubError::InvalidAmount)));
+    t.hub.set_fee_split(&t.admin, &split);
+    assert_eq!(t.hub.get_fee_split(), split);
+
+    t.mint(&t.blnd, &staker_a, 100 * UNIT);
+    t.mint(&t.blnd, &staker_b, 300 * UNIT);
+    t.hub.stake_blend(&staker_a, &(100 * UNIT));
+    t.hub.stake_blend(&staker_b, &(300 * UNIT));
+
+    // Fees are kept per token in the token they were paid in
+    t.hub.swap_tokens(&trader, &t.xlm, &t.usdc, &(1_000 * UNIT), &0, &deadline);
+    t.hub.swap_tokens(&trader, &t.usdc, &t.xlm, &(1_000 * UNIT), &0, &deadline);
+    let fees = t.hub.get_accrued_fees();
+    assert_eq!(
+        fees.get(t.xlm.clone()),
+        Some(AccruedFees { stakers: 25_000_000, treasury: 65_000_000, backstop: UNIT })
+    );
+    assert_eq!(
+        fees.get(t.usdc.clone()),
+        Some(AccruedFees { stakers: 25_000_000, treasury: 15_000_000, backstop: UNIT })
+    );
+
+    // Stakers are paid their share of each token pro rata when they unstake
+    t.hub.unstake_blend(&staker_a, &(100 * UNIT));
+    assert_eq!(t.balance(&t.xlm, &staker_a), 6_250_000);
+    assert_eq!(t.balance(&t.usdc, &staker_a), 6_250_000);
+    assert_eq!(t.balance(&t.blnd, &staker_a), 100 * UNIT);
+    t.hub.unstake_blend(&staker_b, &0);
+    assert_eq!(t.balance(&t.xlm, &staker_b), 18_750_000);
+    assert_eq!(t.hub.get_accrued_fees().get(t.xlm.clone()).unwrap().stakers, 0);
+
+    // Fixed-rate swaps can't pay out booked fees
+    let hub_xlm = t.balance(&t.xlm, &t.hub.address);
+    token::Client::new(&t.env, &t.xlm).burn(&t.hub.address, &(hub_xlm - 75_000_000));
+    t.mint(&t.usdc, &trader, UNIT);
+    let result = t.hub.try_swap_tokens(&trader, &t.usdc, &t.xlm, &UNIT, &0, &deadline);
+    assert_eq!(result, Err(Ok(HubError::InsufficientBalance)));
+
+    // Only the treasury share can be withdrawn by the admin
+    let result = t.hub.try_withdraw_fees(&trader, &t.xlm, &treasury, &UNIT);
+    assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+    let result = t.hub.try_withdraw_fees(&t.admin, &t.xlm, &treasury, &(65_000_000 + 1));
+    assert_eq!(result, Err(Ok(HubError::InsufficientBalance)));
+    t.hub.withdraw_fees(&t.admin, &t.xlm, &treasury, &65_000_000);
+    assert_eq!(t.balance(&t.xlm, &treasury), 65_000_000);
+
+    // The backstop share is credited to the pool's backstop
+    let pool_usdc = t.balance(&t.usdc, &t.pool.address);
+    assert_eq!(t.hub.send_backstop_fees(&t.usdc), UNIT);
+    assert_eq!(t.balance(&t.usdc, &t.pool.address), pool_usdc + UNIT);
+    assert_eq!(t.pool.get_reserve(&t.usdc).data.backstop_credit, UNIT);
+    assert_eq!(t.hub.send_backstop_fees(&t.usdc), 0);
+    assert_eq!(t.hub.send_backstop_fees(&t.xlm), UNIT);
+    assert_eq!(t.pool.get_reserve(&t.xlm).data.backstop_credit, UNIT);
+    assert_eq!(t.balance(&t.xlm, &t.hub.address), 0);
+    assert_eq!(
+        t.hub.get_accrued_fees().get(t.usdc.clone()),
+        Some(AccruedFees { stakers: 0, treasury: 15_000_000, backstop: 0 })
+    );
+}
+
+#[test]
+fn test_liquidation_protection() {
+    let t = TestEnv::new();
+    let user = t.borrower();
+    let other = t.borrower();
+    let opted_out = t.borrower();
+    t.hub.set_liquidation_protection(&user, &true);
+    t.hub.set_liquidation_protection(&opted_out, &true);
+    t.hub.set_liquidation_protection(&opted_out, &false);
+    let breaker = CircuitBreakerConfig { max_move_bps: 1_000, window: 3_600, grace_period: 1_800 };
+    t.hub.set_circuit_breaker(&t.admin, &breaker);
+    t.hub.poke_price(&t.xlm);
+
+    let result = t.hub.try_trigger_liquidation_protection(&user);
+    assert_eq!(result, Err(Ok(HubError::PositionNotAtRisk)));
+
+    // XLM rallies to $0.174: $870 of debt, health factor 1.034
+    t.oracle.set_price(&t.xlm, &174_000_000_000_000_000);
+    assert_eq!(t.hub.get_health_status(&user), HealthStatus::Warning);
+    t.hub.poke_price(&t.xlm);
+
+    // Nobody can sell the collateral of users who didn't opt in
+    for user in [&other, &opted_out] {
+        let result = t.hub.try_trigger_liquidation_protection(user);
+        assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+    }
+
+    // Nor while the circuit breaker holds the move
+    let result = t.hub.try_trigger_liquidation_protection(&user);
+    assert_eq!(result, Err(Ok(HubError::PriceGuarded)));
+    t.hub.clear_price_guard(&t.admin, &t.xlm);
+
+    let hub_xlm = t.balance(&t.xlm, &t.hub.address);
+    let hub_usdc = t.balance(&t.usdc, &t.hub.address);
+    let repaid = t.hub.trigger_liquidation_protection(&user);
+    assert!(repaid > 0);
+    let position = t.hub.get_user_position(&user);
+    assert_eq!(position.borrowed_assets.get(t.xlm.clone()), Some(5_000 * UNIT - repaid));
+    assert!(position.health_factor > AUTO_REPAY_THRESHOLD);
+
+    // The repayment is paid for with the user's own collateral, withdrawn from Blend
+    let collateral_used = 1_000 * UNIT - position.collateral_assets.get(t.usdc.clone()).unwrap();
+    assert!(collateral_used > 0);
+    assert_eq!(t.balance(&t.usdc, &t.hub.address), hub_usdc + collateral_used);
+    assert_eq!(t.balance(&t.xlm, &t.hub.address), hub_xlm - repaid);
+}
+
+#[test]
+fn test_liquidate_position() {
+    let t = TestEnv::new();
+    let user = t.borrower();
+    let liquidator = Address::generate(&t.env);
+    t.mint(&t.xlm, &liquidator, 2_000 * UNIT);
+
+    let result = t.hub.try_liquidate_position(&liquidator, &user, &t.xlm, &t.usdc, &(2_000 * UNIT));
+    assert_eq!(result, Err(Ok(HubError::PositionHealthy)));
+
+    // $875 of debt is over the $850 borrow limit but inside the liquidation threshold
+    t.oracle.set_price(&t.xlm, &175_000_000_000_000_000);
+    let result = t.hub.try_liquidate_position(&liquidator, &user, &t.xlm, &t.usdc, &(2_000 * UNIT));
+    assert_eq!(result, Err(Ok(HubError::PositionHealthy)));
+    let result = t.hub.try_borrow_from_blend(&user, &t.xlm, &UNIT);
+    assert_eq!(result, Err(Ok(HubError::UnhealthyPosition)));
+
+    // XLM rallies to $0.19: $950 of debt against $900 of threshold-weighted collateral
+    t.oracle.set_price(&t.xlm, &190_000_000_000_000_000);
+    assert_eq!(t.hub.get_health_status(&user), HealthStatus::Liquidatable);
+
+    let result = t.hub.try_liquidate_position(&liquidator, &user, &t.xlm, &t.usdc, &(5_001 * UNIT));
+    assert_eq!(result, Err(Ok(HubError::InsufficientDebt)));
+
+    // $380 of debt plus the 3% USDC bonus
+    let seized = t.hub.liquidate_position(&liquidator, &user, &t.xlm, &t.usdc, &(2_000 * UNIT));
+    assert_eq!(seized, 3_914_000_000);
+    assert_eq!(t.balance(&t.usdc, &liquidator), seized);
+    assert_eq!(t.balance(&t.xlm, &liquidator), 0);
+
+    let position = t.hub.get_user_position(&user);
+    assert_eq!(position.borrowed_assets.get(t.xlm.clone()), Some(3_000 * UNIT));
+    assert_eq!(position.collateral_assets.get(t.usdc.clone()), Some(1_000 * UNIT - seized));
+}
+
+#[test]
+fn test_twap_liquidation_price() {
+    let t = TestEnv::new();
+    let user = t.borrower();
+    let liquidator = Address::generate(&t.env);
+    t.mint(&t.xlm, &liquidator, 2_000 * UNIT);
+    let advance = |seconds: u64, price: u128| {
+        t.env.ledger().with_mut(|li| li.timestamp += seconds);
+        t.oracle.set_price(&t.xlm, &price);
+    };
+
+    let result = t.hub.try_set_price_mode(&user, &t.xlm, &PriceMode::Twap(600));
+    assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+    for invalid in [PriceMode::Twap(0), PriceMode::Twap(MAX_TWAP_WINDOW + 1)] {
+        let result = t.hub.try_set_price_mode(&t.admin, &t.xlm, &invalid);
+        assert_eq!(result, Err(Ok(HubError::InvalidAssetConfig)));
+    }
+
+    // The borrow recorded the first observation; a window needs history behind it
+    assert_eq!(t.hub.try_get_twap(&t.xlm, &600), Err(Ok(HubError::PriceOracleError)));
+    let result = t.hub.try_set_price_mode(&t.admin, &t.xlm, &PriceMode::Twap(600));
+    assert_eq!(result, Err(Ok(HubError::PriceOracleError)));
+    advance(300, 120_000_000_000_000_000);
+    t.hub.poke_price(&t.xlm);
+
+    // A single-ledger spike moves the spot price but not the TWAP
+    advance(300, 190_000_000_000_000_000);
+    t.hub.poke_price(&t.xlm);
+    t.hub.set_price_mode(&t.admin, &t.xlm, &PriceMode::Twap(600));
+    let result = t.hub.try_liquidate_position(&liquidator, &user, &t.xlm, &t.usdc, &(2_000 * UNIT));
+    assert_eq!(result, Err(Ok(HubError::PositionHealthy)));
+    assert_eq!(t.hub.get_twap(&t.xlm, &600), 120_000_000_000_000_000);
+
+    // Once the new price holds for the whole window the position is liquidatable
+    advance(300, 190_000_000_000_000_000);
+    t.hub.poke_price(&t.xlm);
+    advance(300, 190_000_000_000_000_000);
+    assert_eq!(t.hub.get_twap(&t.xlm, &600), 190_000_000_000_000_000);
+    assert_eq!(t.hub.get_twap(&t.xlm, &1_200), 155_000_000_000_000_000);
+    let seized = t.hub.liquidate_position(&liquidator, &user, &t.xlm, &t.usdc, &(2_000 * UNIT));
+    assert_eq!(seized, 3_914_000_000);
+}
+
+#[test]
+fn test_price_circuit_breaker() {
+    let t = TestEnv::new();
+    let user = t.borrower();
+    let liquidator = Address::generate(&t.env);
+    t.mint(&t.xlm, &liquidator, 2_000 * UNIT);
+    let config = CircuitBreakerConfig { max_move_bps: 1_000, window: 3_600, grace_period: 1_800 };
+
+    let result = t.hub.try_set_circuit_breaker(&user, &config);
+    assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+    let result = t.hub.try_set_circuit_breaker(&t.admin, &CircuitBreakerConfig { window: 0, ..config.clone() });
+    assert_eq!(result, Err(Ok(HubError::InvalidAmount)));
+    t.hub.set_circuit_breaker(&t.admin, &config);
+    t.hub.borrow_from_blend(&user, &t.wbtc, &1_000);
+    t.hub.poke_price(&t.xlm);
+    t.hub.poke_price(&t.wbtc);
+
+    // A 58% print blocks new borrows and liquidations of the asset
+    t.oracle.set_price(&t.xlm, &190_000_000_000_000_000);
+    let result = t.hub.try_borrow_from_blend(&user, &t.xlm, &UNIT);
+    assert_eq!(result, Err(Ok(HubError::PriceGuarded)));
+    let result = t.hub.try_liquidate_position(&liquidator, &user, &t.xlm, &t.usdc, &(2_000 * UNIT));
+    assert_eq!(result, Err(Ok(HubError::PriceGuarded)));
+
+    // Releasing collateral against debt is held too
+    let result = t.hub.try_withdraw_from_blend(&user, &t.usdc, &UNIT);
+    assert_eq!(result, Err(Ok(HubError::PriceGuarded)));
+    let result = t.hub.try_disable_collateral(&user, &t.usdc);
+    assert_eq!(result, Err(Ok(HubError::PriceGuarded)));
+
+    // Any reading persists the guard; one in the same ledger can't clear it
+    t.hub.poke_price(&t.xlm);
+    t.hub.poke_price(&t.xlm);
+    let guard = t.hub.get_price_guard(&t.xlm).unwrap();
+    assert_eq!(guard.accepted_price, 120_000_000_000_000_000);
+    assert_eq!(guard.pending_price, 190_000_000_000_000_000);
+    assert_eq!(guard.until, t.env.ledger().timestamp() + 1_800);
+
+    // A later reading back at the old price clears it
+    t.env.ledger().with_mut(|li| li.timestamp += 5);
+    t.oracle.set_price(&t.xlm, &120_000_000_000_000_000);
+    t.hub.poke_price(&t.xlm);
+    assert_eq!(t.hub.get_price_guard(&t.xlm), None);
+    t.hub.borrow_from_blend(&user, &t.xlm, &UNIT);
+
+    // The admin can accept the move instead
+    t.oracle.set_price(&t.xlm, &190_000_000_000_000_000);
+    t.hub.poke_price(&t.xlm);
+    assert!(t.hub.get_price_guard(&t.xlm).is_some());
+    let result = t.hub.try_clear_price_guard(&user, &t.xlm);
+    assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+    t.hub.clear_price_guard(&t.admin, &t.xlm);
+    assert_eq!(t.hub.get_price_guard(&t.xlm), None);
+
+    // Any other asset in the position also holds borrows and liquidations
+    t.oracle.set_price(&t.wbtc, &(135_000 * PRICE_SCALE));
+    t.hub.poke_price(&t.wbtc);
+    let result = t.hub.try_liquidate_position(&liquidator, &user, &t.xlm, &t.usdc, &(2_000 * UNIT));
+    assert_eq!(result, Err(Ok(HubError::PriceGuarded)));
+    let result = t.hub.try_borrow_from_blend(&user, &t.usdc, &UNIT);
+    assert_eq!(result, Err(Ok(HubError::PriceGuarded)));
+    t.oracle.set_price(&t.wbtc, &(45_000 * PRICE_SCALE));
+    t.env.ledger().with_mut(|li| li.timestamp += 5);
+    t.hub.poke_price(&t.wbtc);
+    t.hub.liquidate_position(&liquidator, &user, &t.xlm, &t.usdc, &(2_000 * UNIT));
+}
+
+#[test]
+fn test_health_factor_budget() {
+    let t = TestEnv::new();
+    let user = t.borrower();
+    t.mint(&t.weth, &user, UNIT);
+    t.hub.supply_to_blend(&user, &t.weth, &(UNIT / 10), &true);
+    t.hub.borrow_from_blend(&user, &t.blnd, &(100 * UNIT));
+    t.hub.set_liquidation_protection(&user, &true);
+    let liquidator = Address::generate(&t.env);
+    t.mint(&t.xlm, &liquidator, 2_000 * UNIT);
+
+    // Prices, configs and reserves are read once per invocation; before the
+    // snapshot these calls cost ~15.0M and ~18.9M CPU instructions
+    t.env.budget().reset_unlimited();
+    t.hub.borrow_from_blend(&user, &t.xlm, &UNIT);
+    assert!(t.env.budget().cpu_instruction_cost() < 7_000_000);
+
+    t.oracle.set_price(&t.xlm, &230_000_000_000_000_000);
+    t.env.budget().reset_unlimited();
+    t.hub.liquidate_position(&liquidator, &user, &t.xlm, &t.usdc, &(1_000 * UNIT));
+    assert!(t.env.budget().cpu_instruction_cost() < 8_500_000);
+}
+
+#[test]
+fn test_admin_only_entrypoints() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+
+    let result = t.hub.try_update_exchange_rate(&user, &t.xlm, &t.usdc, &PRICE_SCALE);
+    assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+
+    let result = t.hub.try_emergency_withdraw(&user, &user, &t.xlm, &UNIT);
+    assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+
+    let config = ReconcileConfig { tolerance_bps: 0, pause_on_drift: true };
+    let result = t.hub.try_set_reconcile_config(&user, &config);
+    assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+
+    let result = t.hub.try_unpause_asset(&user, &t.xlm);
+    assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+}
+
+#[test]
+fn test_asset_registry() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+    let eurc = t.env.register_stellar_asset_contract_v2(t.admin.clone()).address();
+    let config = AssetConfig {
+        address: eurc.clone(),
+        symbol: String::from_str(&t.env, "EURC"),
+        decimals: 0,
+        is_active: true,
+        ltv_ratio: 8000,
+        liquidation_threshold_bps: 8500,
+        reserve_index: 5,
+        liquidation_bonus: 400,
+        max_price_age: 3600,
+    };
+
+    assert_eq!(t.hub.get_supported_assets().len(), 5);
+
+    let result = t.hub.try_add_asset(&user, &config);
+    assert_eq!(result, Err(Ok(HubError::Unauthorized)));
+    for invalid in [
+        AssetConfig { liquidation_threshold_bps: 10000, ..config.clone() },
+        AssetConfig { ltv_ratio: 9000, ..config.clone() },
+        AssetConfig { liquidation_bonus: 2001, ..config.clone() },
+        AssetConfig { max_price_age: 299, ..config.clone() },
+    ] {
+        let result = t.hub.try_add_asset(&t.admin, &invalid);
+        assert_eq!(result, Err(Ok(HubError::InvalidAssetConfig)));
+    }
+    let wide = t.env.register_contract(None, wide_token::MockWideToken);
+    let result = t.hub.try_add_asset(&t.admin, &AssetConfig { address: wide, ..config.clone() });
+    assert_eq!(result, Err(Ok(HubError::InvalidAssetConfig)));
+
+    // Decimals come from the token contract
+    t.hub.add_asset(&t.admin, &config);
+    let assets = t.hub.get_supported_assets();
+    assert_eq!(assets.len(), 6);
+    assert_eq!(assets.get(5), Some(AssetConfig { decimals: 7, ..config.clone() }));
+
+    let result = t.hub.try_add_asset(&t.admin, &config);
+    assert_eq!(result, Err(Ok(HubError::AssetAlreadyExists)));
+
+    let updated = AssetConfig { ltv_ratio: 7500, liquidation_bonus: 500, reserve_index: 6, ..config.clone() };
+    t.hub.update_asset_config(&t.admin, &updated);
+    assert_eq!(t.hub.get_supported_assets().get(5), Some(AssetConfig { decimals: 7, ..updated }));
+    let result = t.hub.try_update_asset_config(&t.admin, &AssetConfig { address: user.clone(), ..config });
+    assert_eq!(result, Err(Ok(HubError::InvalidAsset)));
+
+    // Deactivated assets stay listed but reject new supply
+    t.hub.deactivate_asset(&t.admin, &t.weth);
+    assert!(!t.hub.get_supported_assets().get(3).unwrap().is_active);
+    t.mint(&t.weth, &user, UNIT);
+    let result = t.hub.try_supply_to_blend(&user, &t.weth, &UNIT, &true);
+    assert_eq!(result, Err(Ok(HubError::InvalidAsset)));
+}
+
+#[test]
+fn test_reconcile() {
+    let t = TestEnv::new();
+    let user = t.borrower();
+    let liquidator = Address::generate(&t.env);
+    t.mint(&t.xlm, &liquidator, 2_000 * UNIT);
+    t.oracle.set_price(&t.xlm, &190_000_000_000_000_000);
+    t.hub.liquidate_position(&liquidator, &user, &t.xlm, &t.usdc, &(2_000 * UNIT));
+
+    // Hub accounting matches Blend after supply, borrow and liquidation
+    let report = t.hub.reconcile(&t.usdc);
+    assert_eq!(report.hub_collateral, 1_000 * UNIT - 3_914_000_000);
+    assert_eq!(report.blend_collateral, report.hub_collateral);
+    assert_eq!(report.drift_bps, 0);
+    let report = t.hub.reconcile(&t.xlm);
+    assert_eq!((report.hub_borrowed, report.blend_borrowed), (3_000 * UNIT, 3_000 * UNIT));
+    assert_eq!(report.drift_bps, 0);
+
+    // Blend reports 5% less XLM debt than the hub recorded
+    let mut positions = t.pool.get_positions(&t.hub.address);
+    positions.liabilities.set(0, 2_850 * UNIT);
+    t.pool.set_positions(&t.hub.address, &positions);
+
+    // Reported but not paused by default
+    let report = t.hub.reconcile(&t.xlm);
+    assert_eq!(report.drift_bps, 526);
+    assert!(!report.paused);
+
+    let config = ReconcileConfig { tolerance_bps: 500, pause_on_drift: true };
+    t.hub.set_reconcile_config(&t.admin, &config);
+    assert!(t.hub.reconcile(&t.xlm).paused);
+
+    // Paused assets reject new borrows but can still be repaid
+    let result = t.hub.try_borrow_from_blend(&user, &t.xlm, &UNIT);
+    assert_eq!(result, Err(Ok(HubError::InvalidAsset)));
+    t.hub.repay_to_blend(&user, &user, &t.xlm, &UNIT);
+
+    t.oracle.set_price(&t.xlm, &120_000_000_000_000_000);
+    t.hub.unpause_asset(&t.admin, &t.xlm);
+    t.hub.borrow_from_blend(&user, &t.xlm, &UNIT);
+}
+
+#[test]
+fn test_bump_position() {
+    let t = TestEnv::new();
+    let user = Address::generate(&t.env);
+
+    let result = t.hub.try_bump_position(&user);
+    assert_eq!(result, Err(Ok(HubError::PositionNotFound)));
+
+    // (position TTL, instance TTL) in ledgers
+    let ttls = || {
+        t.env.as_contract(&t.hub.address, || {
+            let storage = t.env.storage();
+            (storage.persistent().get_ttl(&DataKey::Position(user.clone())), storage.instance().get_ttl())
+        })
+    };
+    let two_days = || t.env.ledger().with_mut(|li| li.sequence_number += 2 * DAY_IN_LEDGERS);
+
+    // Writing a position bumps both TTLs
+    t.mint(&t.xlm, &user, 2 * UNIT);
+    t.hub.supply_to_blend(&user, &t.xlm, &UNIT, &true);
+    assert_eq!(ttls(), (PERSISTENT_BUMP_AMOUNT, INSTANCE_BUMP_AMOUNT));
+
+    // Below the threshold a keeper's bump restores them
+    two_days();
+    assert_eq!(ttls(), (PERSISTENT_BUMP_AMOUNT - 2 * DAY_IN_LEDGERS, INSTANCE_BUMP_AMOUNT - 2 * DAY_IN_LEDGERS));
+    t.hub.bump_position(&user);
+    assert_eq!(ttls(), (PERSISTENT_BUMP_AMOUNT, INSTANCE_BUMP_AMOUNT));
+
+    // And so does the user's next supply
+    two_days();
+    t.hub.supply_to_blend(&user, &t.xlm, &UNIT, &true);
+    assert_eq!(ttls(), (PERSISTENT_BUMP_AMOUNT, INSTANCE_BUMP_AMOUNT));
+}
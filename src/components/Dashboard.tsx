@@ -4,6 +4,7 @@ import { getUserPosition, getHealthStatus, getAssetPrice, TOKEN_ADDRESSES } from
 import { toast } from 'sonner';
 
 interface UserPosition {
+  collateral_assets: Record<string, string>;
   supplied_assets: Record<string, string>;
   borrowed_assets: Record<string, string>;
   staked_blend: string;
@@ -106,13 +107,16 @@ const Dashboard: React.FC<DashboardProps> = ({ balances }) => {
     if (!userPosition) return {};
     const supplied: Record<string, number> = {};
     
-    Object.entries(userPosition.supplied_assets).forEach(([address, amount]) => {
-      const symbol = Object.keys(TOKEN_ADDRESSES).find(key => 
-        TOKEN_ADDRESSES[key as keyof typeof TOKEN_ADDRESSES] === address
-      );
-      if (symbol) {
-        supplied[symbol] = parseFloat(formatAmount(amount, getTokenDecimals(symbol)));
-      }
+    // Collateral and lend-only balances are both supplied to Blend
+    [userPosition.collateral_assets, userPosition.supplied_assets].forEach((balances) => {
+      Object.entries(balances).forEach(([address, amount]) => {
+        const symbol = Object.keys(TOKEN_ADDRESSES).find(key => 
+          TOKEN_ADDRESSES[key as keyof typeof TOKEN_ADDRESSES] === address
+        );
+        if (symbol) {
+          supplied[symbol] = (supplied[symbol] || 0) + parseFloat(formatAmount(amount, getTokenDecimals(symbol)));
+        }
+      });
     });
     
     return supplied;
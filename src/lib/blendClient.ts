@@ -319,8 +319,8 @@ export async function swapTokens(
       stringToAddress(userAddress),
       stringToAddress(tokenInAddress),
       stringToAddress(tokenOutAddress),
-      nativeToScVal(amountInFormatted, { type: 'u128' }),
-      nativeToScVal(minAmountOutFormatted, { type: 'u128' }),
+      nativeToScVal(amountInFormatted, { type: 'i128' }),
+      nativeToScVal(minAmountOutFormatted, { type: 'i128' }),
       nativeToScVal(deadline, { type: 'u64' })
     ];
 
@@ -360,7 +360,7 @@ export async function borrowFromBlend(
     const args = [
       stringToAddress(userAddress),
       stringToAddress(assetAddress),
-      nativeToScVal(amountFormatted, { type: 'u128' })
+      nativeToScVal(amountFormatted, { type: 'i128' })
     ];
 
     return await buildAndSubmitSorobanTransaction(
@@ -391,7 +391,7 @@ export async function stakeBlend(
 
     const args = [
       stringToAddress(userAddress),
-      nativeToScVal(amountFormatted, { type: 'u128' })
+      nativeToScVal(amountFormatted, { type: 'i128' })
     ];
 
     return await buildAndSubmitSorobanTransaction(